@@ -0,0 +1,154 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{read_dir, read_link, DirEntry, File, FileType, Metadata};
+use std::io::{copy, stdout, Result as IoResult, Write};
+use std::path::Path;
+
+use clap::Clap;
+
+use la_tools::git_index;
+use la_tools::git_index::{EntryHeader, FileHeader, OwnedEntry, OwnedView, NAME_LENGTH_MASK};
+use la_tools::git_object;
+use la_tools::git_object::Digest;
+
+#[derive(Clap)]
+struct Opts {
+    dir: String,
+    // keep the times, device, inode and owner of the files, rather than zeroing them so the same
+    // tree always gives the same index
+    #[clap(long)]
+    stat: bool,
+}
+
+const MODE_FILE: u32 = 0o100644;
+const MODE_EXECUTABLE: u32 = 0o100755;
+const MODE_SYMLINK: u32 = 0o120000;
+
+#[cfg(unix)]
+fn fill_header(header: &mut EntryHeader, m: &Metadata) {
+    use std::os::unix::fs::MetadataExt;
+    header.mode.set(if m.mode() & 0o111 != 0 {
+        MODE_EXECUTABLE
+    } else {
+        MODE_FILE
+    });
+    header.ctime_s.set(m.ctime() as i32);
+    header.ctime_ns.set(m.ctime_nsec() as i32);
+    header.mtime_s.set(m.mtime() as i32);
+    header.mtime_ns.set(m.mtime_nsec() as i32);
+    header.dev.set(m.dev() as u32);
+    header.ino.set(m.ino() as u32);
+    header.uid.set(m.uid());
+    header.gid.set(m.gid());
+}
+
+#[cfg(not(unix))]
+fn fill_header(header: &mut EntryHeader, _m: &Metadata) {
+    header.mode.set(MODE_FILE);
+}
+
+// the bytes of the path a symlink points to, or None if it isn't one
+#[cfg(unix)]
+fn symlink_target(dir_entry: &DirEntry, file_type: FileType) -> IoResult<Option<Vec<u8>>> {
+    use std::os::unix::ffi::OsStrExt;
+    if !file_type.is_symlink() {
+        return Ok(None);
+    }
+    let target = read_link(dir_entry.path())?;
+    Ok(Some(target.as_os_str().as_bytes().to_vec()))
+}
+
+// Windows symlinks don't map onto git's, so they get skipped like other special files
+#[cfg(not(unix))]
+fn symlink_target(_dir_entry: &DirEntry, _file_type: FileType) -> IoResult<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+// Collects an entry for every regular file and symlink under dir, apart from any .git directory,
+// named by its path relative to the root with '/' separators. They come out in directory order,
+// to be sorted once at the end.
+fn add_dir(entries: &mut Vec<OwnedEntry>, dir: &Path, prefix: &str) -> Result<(), Box<dyn Error>> {
+    for dir_entry in read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let file_name = dir_entry.file_name();
+        let file_name = file_name
+            .to_str()
+            .ok_or_else(|| format!("{} is not UTF-8", dir_entry.path().display()))?;
+        // git never tracks its own directory
+        if file_name == ".git" {
+            continue;
+        }
+        let name = format!("{}{}", prefix, file_name);
+        let file_type = dir_entry.file_type()?;
+        if file_type.is_dir() {
+            add_dir(entries, &dir_entry.path(), &format!("{}/", name))?;
+            continue;
+        }
+        // the metadata of a symlink itself, not what it points to
+        let m = dir_entry.metadata()?;
+        let mut header = EntryHeader::default();
+        fill_header(&mut header, &m);
+        let (size, hash) = if file_type.is_file() {
+            let mut digest = git_object::hash_sync(m.len());
+            copy(&mut File::open(dir_entry.path())?, &mut digest)?;
+            (m.len(), digest.finalize())
+        } else if let Some(target) = symlink_target(&dir_entry, file_type)? {
+            // git stores a symlink as a blob of its target path
+            header.mode.set(MODE_SYMLINK);
+            let mut digest = git_object::hash_sync(target.len() as u64);
+            digest.update(&target);
+            (target.len() as u64, digest.finalize())
+        } else {
+            let path = dir_entry.path();
+            eprintln!("Skipping {}, not a regular file or symlink", path.display());
+            continue;
+        };
+        // git keeps only the low 32 bits of the size
+        header.size.set(size as u32);
+        header.sha1.copy_from_slice(&hash);
+        let name_length = name.len().min(NAME_LENGTH_MASK as usize);
+        header.flags.set(name_length as u16);
+        entries.push(OwnedEntry {
+            header,
+            extended_flags: None,
+            name: name.into_bytes(),
+        });
+    }
+    Ok(())
+}
+
+pub fn try_main<I, T>(itr: I) -> Result<i32, Box<dyn Error>>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let opts = Opts::parse_from(itr);
+
+    let mut header = FileHeader::default();
+    header.magic.copy_from_slice(b"DIRC");
+    header.version.set(2);
+    let mut entries = Vec::new();
+    add_dir(&mut entries, Path::new(&opts.dir), "")?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    header.entry_count.set(entries.len() as u32);
+    let index = OwnedView {
+        header,
+        entries,
+        footer: Vec::new(),
+    };
+    // a name can only turn up once, but git and every lookup depend on the order
+    if !index.is_sorted() {
+        eprintln!("Entries ended up out of order, not writing the index");
+        return Ok(2);
+    }
+
+    let mut out = Vec::<u8>::new();
+    if opts.stat {
+        git_index::write(&index.view(), &mut out);
+    } else {
+        git_index::write_normalized(&index.view(), &mut out);
+    }
+    stdout().write_all(&out)?;
+
+    Ok(0)
+}
@@ -0,0 +1,5 @@
+use create_git_index::try_main;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::process::exit(try_main(std::env::args_os())?)
+}
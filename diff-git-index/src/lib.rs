@@ -0,0 +1,106 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::read;
+
+use clap::Clap;
+use serde_json::json;
+
+use la_tools::git_index;
+use la_tools::git_index::{entry_json, OutputFormat};
+
+#[derive(Clap)]
+struct Opts {
+    old: String,
+    new: String,
+    // text or json
+    #[clap(long, default_value = "text")]
+    format: String,
+}
+
+pub fn try_main<I, T>(itr: I) -> Result<i32, Box<dyn Error>>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let opts = Opts::parse_from(itr);
+
+    let format = match OutputFormat::from_name(&opts.format) {
+        Some(format) => format,
+        None => {
+            eprintln!("Unknown format {}, must be text or json", opts.format);
+            return Ok(1);
+        }
+    };
+
+    let old_data = read(&opts.old)?;
+    let new_data = read(&opts.new)?;
+    let (old_index, new_index) = match (git_index::parse(&old_data), git_index::parse(&new_data)) {
+        (Ok(old_index), Ok(new_index)) => (old_index, new_index),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Parse error: {}", e);
+            return Ok(2);
+        }
+    };
+
+    // both are in index order, so each group comes out sorted by name
+    let added: Vec<_> = new_index
+        .entries
+        .iter()
+        .filter(|e| old_index.find(&e.name).is_none())
+        .collect();
+    let removed: Vec<_> = old_index
+        .entries
+        .iter()
+        .filter(|e| new_index.find(&e.name).is_none())
+        .collect();
+    let modified: Vec<_> = old_index
+        .entries
+        .iter()
+        .filter_map(|old| {
+            let new = new_index.find(&old.name)?;
+            if new.header.size != old.header.size || new.header.sha1 != old.header.sha1 {
+                Some((old, new))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if format == OutputFormat::Json {
+        let modified: Vec<_> = modified
+            .iter()
+            .map(|(old, new)| json!({"old": entry_json(old), "new": entry_json(new)}))
+            .collect();
+        println!(
+            "{}",
+            json!({
+                "added": added.iter().map(|e| entry_json(e)).collect::<Vec<_>>(),
+                "removed": removed.iter().map(|e| entry_json(e)).collect::<Vec<_>>(),
+                "modified": modified,
+            })
+        );
+    } else {
+        for e in added.iter() {
+            println!("+ {}", e);
+        }
+        for e in removed.iter() {
+            println!("- {}", e);
+        }
+        for (old, new) in modified.iter() {
+            println!(
+                "~ {} (was {} {})",
+                new,
+                git_index::hash_to_hex(&old.header.sha1),
+                old.header.size.get()
+            );
+        }
+        println!(
+            "{} added, {} removed, {} modified",
+            added.len(),
+            removed.len(),
+            modified.len()
+        );
+    }
+
+    Ok(0)
+}
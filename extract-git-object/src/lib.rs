@@ -1,20 +1,94 @@
 use std::error::Error;
 use std::ffi::OsString;
-use std::io::{copy, stdin, stdout};
+use std::fs::{create_dir_all, File};
+use std::io::{copy, stdin, stdout, Read, Write};
+use std::path::Path;
+
+use clap::Clap;
 
 use la_tools::git_object;
 
-pub fn try_main<I, T>(_itr: I) -> Result<i32, Box<dyn Error>>
+#[derive(Clap)]
+struct Opts {
+    // objects to decode, or stdin to stdout if there are none
+    files: Vec<String>,
+    // zlib like git, or gzip for mirrors that serve that instead
+    #[clap(long, default_value = "zlib")]
+    format: String,
+    // where decoded files go, named after their objects
+    #[clap(long, default_value = ".")]
+    output_dir: String,
+    // the input is a raw deflate stream rather than zlib
+    #[clap(long, conflicts_with = "format")]
+    raw: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Container {
+    Gzip,
+    Raw,
+    Zlib,
+}
+
+fn decode<'a, R: Read + 'a>(read: R, container: Container) -> Box<dyn Read + 'a> {
+    match container {
+        Container::Gzip => Box::new(git_object::decode_sync_gzip(read)),
+        Container::Raw => Box::new(git_object::decode_sync_raw(read)),
+        Container::Zlib => Box::new(git_object::decode_sync(read)),
+    }
+}
+
+pub fn try_main<I, T>(itr: I) -> Result<i32, Box<dyn Error>>
 where
     I: IntoIterator<Item = T>,
     T: Into<OsString> + Clone,
 {
-    let in_file = stdin();
-    let mut out_file = stdout();
+    let opts = Opts::parse_from(itr);
+
+    let container = match opts.format.as_str() {
+        _ if opts.raw => Container::Raw,
+        "zlib" => Container::Zlib,
+        "gzip" => Container::Gzip,
+        _ => {
+            eprintln!("Unknown format {}, must be zlib or gzip", opts.format);
+            return Ok(1);
+        }
+    };
+
+    if opts.files.is_empty() {
+        let in_file = stdin();
+        let mut out_file = stdout();
+
+        let mut decode_read = decode(in_file, container);
+
+        copy(&mut decode_read, &mut out_file)?;
+
+        return Ok(0);
+    }
+
+    let out_dir = Path::new(&opts.output_dir);
+    create_dir_all(out_dir)?;
 
-    let mut decode_read = git_object::decode_sync(in_file);
+    for file_name in opts.files.iter() {
+        let in_path = Path::new(file_name);
+        let out_path = match in_path.file_name() {
+            Some(name) => out_dir.join(name),
+            None => {
+                eprintln!("{} is not a file", file_name);
+                return Ok(1);
+            }
+        };
+        if out_path.exists() && out_path.canonicalize()? == in_path.canonicalize()? {
+            eprintln!("{} would be decoded over itself, pick another --output-dir", file_name);
+            return Ok(1);
+        }
 
-    copy(&mut decode_read, &mut out_file)?;
+        let mut decode_read = decode(File::open(in_path)?, container);
+        let mut out_file = File::create(&out_path)?;
+        copy(&mut decode_read, &mut out_file)?;
+        out_file.flush()?;
+        eprintln!("{} -> {}", file_name, out_path.display());
+    }
 
     Ok(0)
 }
@@ -0,0 +1,68 @@
+use std::cmp::{max, min};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{Semaphore, TryAcquireError};
+
+pub const START_PERMITS: usize = 8;
+const ADJUST_INTERVAL: Duration = Duration::from_secs(2);
+const PERMITS_STEP: usize = 2;
+
+// Adjusts the permits of sem, which limits the requests in flight, from the download rate seen
+// through downloaded. Permits are added a few at a time while the rate holds up, and half are
+// taken away when it drops by more than a quarter, like TCP congestion control. Permits held by
+// requests in flight are taken back as they are returned, a tick at a time. Runs until aborted.
+pub async fn run(
+    sem: Arc<Semaphore>,
+    downloaded: Arc<AtomicU64>,
+    mut permits: usize,
+    max_permits: usize,
+) {
+    let mut interval = tokio::time::interval(ADJUST_INTERVAL);
+    // the first tick is immediate
+    interval.tick().await;
+    let mut last = downloaded.load(Ordering::Relaxed);
+    let mut last_rate = 0u64;
+    // permits taken away but still held by requests in flight
+    let mut owed = 0usize;
+    loop {
+        interval.tick().await;
+        let now = downloaded.load(Ordering::Relaxed);
+        // a repair takes the file's bytes back off downloaded
+        let rate = now.saturating_sub(last);
+        last = now;
+        if rate * 4 < last_rate * 3 && permits > 1 {
+            let target = max(permits / 2, 1);
+            owed += permits - target;
+            permits = target;
+            log!(
+                "Download rate dropped, lowering network concurrency to {}",
+                permits
+            );
+        } else if permits < max_permits {
+            let step = min(PERMITS_STEP, max_permits - permits);
+            // any still owed cancel out first
+            let cancelled = min(owed, step);
+            owed -= cancelled;
+            sem.add_permits(step - cancelled);
+            permits += step;
+            if permits == max_permits {
+                log!("Network concurrency is at its maximum of {}", permits);
+            }
+        }
+        last_rate = rate;
+        // Never waits, since tokio semaphores are fair and a pending acquire would hold up every
+        // new request behind it until enough in flight finished.
+        while owed > 0 {
+            match sem.try_acquire() {
+                Ok(taken) => {
+                    taken.forget();
+                    owed -= 1;
+                }
+                Err(TryAcquireError::NoPermits) => break,
+                Err(TryAcquireError::Closed) => return,
+            }
+        }
+    }
+}
@@ -1,29 +1,183 @@
-use std::fs::OpenOptions;
-use std::io::{Result as IoResult, Seek, SeekFrom, Write};
+use std::fs::{metadata, read, remove_file, File, OpenOptions};
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
 use std::path::Path;
 
 use memmap2::{MmapMut, MmapOptions};
 
-pub fn create_mmap<P: AsRef<Path>>(
+const MARKS_HEADER_LEN: usize = 8;
+
+// Where a chunk of a .tmp file gets written. Mapping is preferred, but some filesystems like
+// network mounts can't do it, so there is a fallback to plain positional writes.
+pub enum ChunkWriter {
+    Mmap(MmapMut),
+    Buffered { f: File, offset: u64, len: usize },
+}
+
+impl ChunkWriter {
+    pub fn write_chunk(&mut self, data: &[u8]) -> IoResult<()> {
+        let len = match self {
+            ChunkWriter::Mmap(m) => m.len(),
+            ChunkWriter::Buffered { len, .. } => *len,
+        };
+        if data.len() != len {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!("chunk is {} bytes, expected {}", data.len(), len),
+            ));
+        }
+        match self {
+            ChunkWriter::Mmap(m) => m.copy_from_slice(data),
+            ChunkWriter::Buffered { f, offset, .. } => {
+                f.seek(SeekFrom::Start(*offset))?;
+                f.write_all(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> IoResult<()> {
+        match self {
+            ChunkWriter::Mmap(m) => m.flush_async(),
+            ChunkWriter::Buffered { f, .. } => f.flush(),
+        }
+    }
+}
+
+// Passes everything read from r on to w as well, e.g. to write out what is being hashed.
+pub struct TeeRead<R, W> {
+    pub r: R,
+    pub w: W,
+}
+
+impl<R: Read, W: Write> Read for TeeRead<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let amt = self.r.read(buf)?;
+        self.w.write_all(&buf[..amt])?;
+        Ok(amt)
+    }
+}
+
+pub fn create_chunk_writer<P: AsRef<Path>>(
     path: P,
     fsize: u64,
     offset: u64,
     len: usize,
-) -> IoResult<MmapMut> {
-    //eprintln!("create_mmap({}, {}, {}, {}) open", path.as_ref().display(), fsize, offset, len);
-    let mut f = OpenOptions::new()
+) -> IoResult<ChunkWriter> {
+    //eprintln!("create_chunk_writer({}, {}, {}, {}) open", path.as_ref().display(), fsize, offset, len);
+    let f = OpenOptions::new()
         .create(true)
+        .truncate(false)
         .read(true)
         .write(true)
         .open(path)?;
-    //eprintln!("create_mmap({}, {}, {}, {}) seek", path.as_ref().display(), fsize, offset, len);
-    f.seek(SeekFrom::Start(fsize - 1))?;
-    //eprintln!("create_mmap({}, {}, {}, {}) write", path.as_ref().display(), fsize, offset, len);
-    f.write(&[b'\0'])?;
+    // size the whole file up front, so any chunk can be mapped whichever order they arrive in
+    //eprintln!("create_chunk_writer({}, {}, {}, {}) set_len", path.as_ref().display(), fsize, offset, len);
+    if f.metadata()?.len() != fsize {
+        f.set_len(fsize)?;
+    }
     let mut opts = MmapOptions::new();
     opts.offset(offset).len(len);
-    //eprintln!("create_mmap({}, {}, {}, {}) map_mut", path.as_ref().display(), fsize, offset, len);
-    let m = unsafe { opts.map_mut(&f) }?;
-    //eprintln!("create_mmap({}, {}, {}, {}) ok", path.as_ref().display(), fsize, offset, len);
-    Ok(m)
+    //eprintln!("create_chunk_writer({}, {}, {}, {}) map_mut", path.as_ref().display(), fsize, offset, len);
+    match unsafe { opts.map_mut(&f) } {
+        Ok(m) => Ok(ChunkWriter::Mmap(m)),
+        Err(_) => Ok(ChunkWriter::Buffered { f, offset, len }),
+    }
+}
+
+// A .tmp of the full size without any marks, as left by a download that finished but was never
+// decoded, e.g. by a version that didn't keep marks. One that was cut short looks the same since
+// the .tmp is sized up front, so only decoding it can tell them apart.
+pub fn is_unmarked_tmp<P: AsRef<Path>, Q: AsRef<Path>>(
+    tmp_path: P,
+    marks_path: Q,
+    fsize: u64,
+) -> bool {
+    metadata(&marks_path).is_err() && metadata(&tmp_path).is_ok_and(|m| m.len() == fsize)
+}
+
+// The marks file starts with the chunk size as a big-endian u64, since marks made with a different
+// chunk size would describe different ranges, followed by a byte per chunk of the .tmp file that is
+// set once that chunk has been written. Returns which chunks are already done, throwing away a .tmp
+// that doesn't match.
+pub fn load_chunk_marks<P: AsRef<Path>, Q: AsRef<Path>>(
+    tmp_path: P,
+    marks_path: Q,
+    fsize: u64,
+    chunk_size: u64,
+) -> Vec<bool> {
+    let chunk_count = fsize.div_ceil(chunk_size);
+    let marks = (|| {
+        if metadata(&tmp_path).ok()?.len() != fsize {
+            return None;
+        }
+        let marks = read(&marks_path).ok()?;
+        let (header, marks) = marks.split_at_checked(MARKS_HEADER_LEN)?;
+        if header != chunk_size.to_be_bytes() || marks.len() as u64 != chunk_count {
+            return None;
+        }
+        Some(marks.iter().map(|&x| x != 0).collect())
+    })();
+    marks.unwrap_or_else(|| {
+        // missing or stale, so start again from nothing
+        let _ = remove_file(&tmp_path);
+        let _ = remove_file(&marks_path);
+        vec![false; chunk_count as usize]
+    })
+}
+
+pub fn mark_chunk_done<P: AsRef<Path>>(
+    marks_path: P,
+    fsize: u64,
+    chunk_size: u64,
+    chunk_i: u64,
+) -> IoResult<()> {
+    let mut f = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(marks_path)?;
+    f.set_len(MARKS_HEADER_LEN as u64 + fsize.div_ceil(chunk_size))?;
+    f.write_all(&chunk_size.to_be_bytes())?;
+    f.seek(SeekFrom::Start(MARKS_HEADER_LEN as u64 + chunk_i))?;
+    f.write_all(&[1])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn middle_chunk_of_new_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp_path = dir.path().join("a.tmp");
+        let marks_path = dir.path().join("a.tmp.marks");
+        // a short last chunk, and nothing on disk yet to say how big the file will be
+        let chunk_len = 4096;
+        let chunk_size = chunk_len as u64;
+        let fsize = chunk_size * 3 + 100;
+        let data = vec![7u8; chunk_len];
+
+        let mut writer = create_chunk_writer(&tmp_path, fsize, chunk_size, chunk_len).unwrap();
+        writer.write_chunk(&data).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(metadata(&tmp_path).unwrap().len(), fsize);
+        let written = read(&tmp_path).unwrap();
+        assert!(written[..chunk_len].iter().all(|&x| x == 0));
+        assert_eq!(written[chunk_len..chunk_len * 2], data[..]);
+        // until it is marked, it can't be told apart from a finished download
+        assert!(is_unmarked_tmp(&tmp_path, &marks_path, fsize));
+
+        mark_chunk_done(&marks_path, fsize, chunk_size, 1).unwrap();
+        assert!(!is_unmarked_tmp(&tmp_path, &marks_path, fsize));
+        let marks = load_chunk_marks(&tmp_path, &marks_path, fsize, chunk_size);
+        assert_eq!(marks, [false, true, false, false]);
+
+        // marks from another chunk size throw the .tmp away
+        let marks = load_chunk_marks(&tmp_path, &marks_path, fsize, chunk_size * 2);
+        assert_eq!(marks, [false, false]);
+        assert!(metadata(&tmp_path).is_err());
+        assert!(metadata(&marks_path).is_err());
+    }
 }
@@ -1,72 +1,332 @@
-#![feature(duration_consts_2)]
-#![feature(iter_zip)]
+#[macro_use]
+mod logging;
 
+mod auto_concurrency;
 mod io_mgr;
+mod mirrors;
+mod rate_limit;
+mod state;
 
-use std::cmp::min;
-use std::collections::BTreeSet;
+use std::cmp::{min, Reverse};
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error;
 use std::ffi::OsString;
 use std::fmt::{Display, Formatter, Result as FmtResult};
-use std::fs::{create_dir_all, metadata, remove_file, File};
-use std::io::{copy, Error as IoError, Write};
+use std::fs::{create_dir_all, metadata, remove_file, rename, File};
+use std::future::Future;
+use std::io::{BufWriter, Error as IoError, ErrorKind, Write};
 use std::iter::zip;
 use std::mem::drop;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use clap::Clap;
-use generic_array::{typenum::U20, GenericArray};
+use fs2::available_space;
 use ini::Ini;
-use reqwest::header::{CONTENT_LENGTH, RANGE};
-use reqwest::{Client, Error as RequestError};
-use tokio::sync::Semaphore;
+use rand::Rng;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use reqwest::{Client, Error as RequestError, Proxy, Response, StatusCode, Url};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::sync::{AcquireError, OnceCell, OwnedSemaphorePermit, Semaphore};
 use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{timeout_at, Instant as TokioInstant};
 use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
-use trust_dns_resolver::AsyncResolver;
+use trust_dns_resolver::{AsyncResolver, TokioAsyncResolver};
 
 use la_tools::git_index;
 use la_tools::git_index::Hash;
 use la_tools::git_object;
 
-use io_mgr::create_mmap;
+use io_mgr::{create_chunk_writer, is_unmarked_tmp, load_chunk_marks, mark_chunk_done, TeeRead};
+use logging::open_log_file;
+use mirrors::Mirrors;
+use rate_limit::RateLimiter;
+use state::{load_state, save_state};
 
+#[derive(Clone)]
 struct FinalFile {
     hash: Hash,
     name: String,
     size: u64,
 }
 
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum FileStatus {
+    Downloaded,
+    Failed,
+    // not finished when the run stopped
+    Incomplete,
+    // already the right size, or completed by an earlier run
+    Skipped,
+    // already the right size, and hashed with --verify-existing
+    Verified,
+}
+
 #[derive(Clap)]
 struct Opts {
+    // start with a few requests at once and adjust from the download rate, up to
+    // --network-threads
+    #[clap(long)]
+    auto_concurrency: bool,
+    // URL of the patch directory holding the indices and objects/, like
+    // http://la.cdn.gameon.jp/la/patch. Defaults to the --region one.
+    #[clap(long, parse(try_from_str = parse_http_url))]
+    base_url: Option<Url>,
+    // in MiB, or with a suffix like 512K or 1G
+    #[clap(long, default_value = "16", parse(try_from_str = parse_chunk_size))]
+    chunk_size: u64,
+    // in seconds, for a chunk's request and body together, after which it is retried like any
+    // other failure. Unlike --request-timeout it leaves the index and other requests alone.
+    // Unlimited by default.
+    #[clap(long)]
+    chunk_timeout: Option<u64>,
+    // in seconds, for opening a connection, unlimited by default
+    #[clap(long)]
+    connect_timeout: Option<u64>,
+    // delete files that were in the previous index but aren't in this one
+    #[clap(long)]
+    delete_removed: bool,
     #[clap(long, default_value = "16")]
     disk_threads: usize,
-    #[clap(long, default_value = "")]
-    output_dir: String,
+    // stop after finding out what would be downloaded, without writing anything
+    #[clap(long)]
+    dry_run: bool,
+    // DoH resolver like https://dns.example/dns-query to use instead of Cloudflare and Google, can
+    // be repeated. Only the host and port are used, and a host name is looked up with system DNS.
+    #[clap(long, multiple_occurrences = true)]
+    doh_server: Vec<String>,
+    // for the --region URLs, the ones given with --base-url and --install-ini-url have their own
+    // scheme. The CDN's certificate may not be valid for the IP found by the DoH lookup, in which
+    // case this needs --system-dns as well.
+    #[clap(long)]
+    https: bool,
+    #[clap(long)]
+    ignore_space: bool,
+    // known SHA-256 of the index from its publisher, to check the download against before
+    // trusting anything in it. Exits with 10 if it doesn't match.
+    #[clap(long, parse(try_from_str = parse_sha256))]
+    index_sha256: Option<String>,
+    // install.ini naming the current version, with version.ini next to it. Defaults to the
+    // --region one.
+    #[clap(long, parse(try_from_str = parse_http_url))]
+    install_ini_url: Option<Url>,
+    // UNSAFE: accept any certificate, even self-signed or for the wrong host, so anyone in the
+    // middle can see and tamper with the download. Only for testing against local mirrors. Needs
+    // an https URL, from --https or given with --base-url or --install-ini-url.
+    #[clap(long)]
+    insecure: bool,
+    // also append everything printed on stderr to this file, with timestamps
+    #[clap(long)]
+    log_file: Option<String>,
+    // JSON file to write the name, hash, size and status of every file in the index to, even if
+    // the run fails part way
+    #[clap(long)]
+    manifest: Option<String>,
+    // total download rate in bytes per second, with an optional suffix like 10M. 0 is unlimited.
+    #[clap(long, default_value = "0", parse(try_from_str = parse_rate))]
+    max_rate: u64,
+    // files being downloaded or decoded at once, to stay under the open file limit
+    #[clap(long, default_value = "256")]
+    max_open_files: usize,
+    // per chunk, before the file is given up on
+    #[clap(long, default_value = "10")]
+    max_retries: u32,
+    // another host to fetch objects from when the --base-url one is failing, with the same
+    // scheme, port and path. Can be repeated.
+    #[clap(long, multiple_occurrences = true)]
+    mirror: Vec<String>,
     #[clap(long, default_value = "64")]
     network_threads: usize,
+    #[clap(long, default_value = "")]
+    output_dir: String,
+    // idle connections kept open to each host, unlimited by default
+    #[clap(long)]
+    pool_max_idle: Option<usize>,
+    #[clap(long)]
+    prefer_ipv6: bool,
+    // index of the version already installed, so files whose hash changed are downloaded even if
+    // their size didn't. Defaults to the one saved in the output directory after the last
+    // successful run.
+    #[clap(long)]
+    previous_index: Option<String>,
+    #[clap(long, default_value = "human")]
+    progress_format: ProgressFormat,
+    // used for all requests instead of HTTP_PROXY/HTTPS_PROXY from the environment. The proxy
+    // resolves hosts itself, so the DoH lookup won't decide which CDN IP is used.
+    #[clap(long)]
+    proxy: Option<String>,
+    // don't print the periodic progress line
+    #[clap(long)]
+    quiet: bool,
+    // which servers --base-url and --install-ini-url default to, only jp so far
+    #[clap(long, default_value = "jp")]
+    region: Region,
+    // times a file is downloaded again from scratch after its hash doesn't match, before giving
+    // up on it
+    #[clap(long, default_value = "1")]
+    repair_attempts: u32,
+    // in seconds, for a whole request including its body, so a stalled chunk is retried. Unlimited
+    // by default.
+    #[clap(long)]
+    request_timeout: Option<u64>,
     #[clap(long)]
     system_dns: bool,
+    #[clap(
+        long,
+        default_value = "PmangDownloader_27cf2b254140ab9a07a7b8615e18d902c0a26edc",
+        parse(try_from_str = parse_user_agent)
+    )]
+    user_agent: String,
+    // hash files that are already the right size, or completed by an earlier run, instead of
+    // trusting them
+    #[clap(long)]
+    verify_existing: bool,
+    // hash every file of the index in the output directory and report which are missing or
+    // corrupt, without downloading or writing anything
+    #[clap(long)]
+    verify_only: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum ProgressFormat {
+    Human,
+    // newline-delimited JSON events on stdout, alongside the usual stderr output
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Region {
+    Jp,
 }
 
 #[derive(Debug)]
 enum TaskError {
+    Acquire(AcquireError),
+    ChunkTimeout,
+    HashMismatch,
+    Interrupted,
     Io(IoError),
     Join(JoinError),
+    // the object is no longer the length its chunks were planned for
+    LengthChanged { planned: u64, actual: u64 },
     Request(RequestError),
+    Status(StatusCode),
 }
 
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 enum MainError {
     DohFail,
     InvalidVersionIni,
-    InvalidGitIndex,
+    InvalidGitIndex(git_index::ParseError),
+}
+
+// What every file's tasks share for the whole run
+struct DownloadContext {
+    client: Client,
+    mirrors: Arc<Mirrors>,
+    net_sem: Arc<Semaphore>,
+    disk_sem: Arc<Semaphore>,
+    files_sem: Arc<Semaphore>,
+    rate_limiter: Option<RateLimiter>,
+    downloaded: Arc<AtomicU64>,
+    interrupted: Arc<AtomicBool>,
+    files_done: Arc<Vec<AtomicBool>>,
+    chunk_size: u64,
+    chunk_timeout: Option<Duration>,
+    max_retries: u32,
+    repair_attempts: u32,
+    json_progress: bool,
+    total_content_length: u64,
+}
+
+// One file to download, shared by its chunk tasks and its disk task
+struct FileJob {
+    ctx: Arc<DownloadContext>,
+    file_i: usize,
+    name: String,
+    hash: Hash,
+    size: u64,
+    // compressed, as downloaded
+    len: u64,
+    dst_path: PathBuf,
+    tmp_path: PathBuf,
+    marks_path: PathBuf,
+    decoded_path: PathBuf,
+    // taken by whichever of the file's tasks starts first, and dropped once all are done
+    file_permit: OnceCell<OwnedSemaphorePermit>,
+    // file_start goes out when the first of its chunks gets to download
+    started: AtomicBool,
+    retry_count: Arc<AtomicU64>,
+}
+
+impl FileStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            FileStatus::Downloaded => "downloaded",
+            FileStatus::Failed => "failed",
+            FileStatus::Incomplete => "incomplete",
+            FileStatus::Skipped => "skipped",
+            FileStatus::Verified => "verified",
+        }
+    }
+}
+
+impl Region {
+    // without the scheme, which comes from --https
+    fn base_url(self) -> &'static str {
+        match self {
+            Region::Jp => "la.cdn.gameon.jp/la/patch",
+        }
+    }
+
+    fn install_ini_url(self) -> &'static str {
+        match self {
+            Region::Jp => "games.cdn.gameon.jp/lostark/install.ini",
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jp" => Ok(Region::Jp),
+            _ => Err(format!("unknown region {}", s)),
+        }
+    }
+}
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(ProgressFormat::Human),
+            "json" => Ok(ProgressFormat::Json),
+            _ => Err(format!("unknown progress format {}", s)),
+        }
+    }
 }
 
 impl Display for TaskError {
-    fn fmt(self: &Self, f: &mut Formatter) -> FmtResult {
-        write!(f, "{:?}", self)
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            TaskError::LengthChanged { planned, actual } => {
+                write!(
+                    f,
+                    "LengthChanged({} bytes, planned for {})",
+                    actual, planned
+                )
+            }
+            TaskError::Status(status) => write!(f, "Status({})", status),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
@@ -74,23 +334,40 @@ impl Error for TaskError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         use TaskError::*;
         match self {
+            Acquire(e) => e.source(),
+            ChunkTimeout => None,
+            HashMismatch => None,
+            Interrupted => None,
             Io(e) => e.source(),
             Join(e) => e.source(),
+            LengthChanged { .. } => None,
             Request(e) => e.source(),
+            Status(_) => None,
         }
     }
 }
 
 impl Display for MainError {
-    fn fmt(self: &Self, f: &mut Formatter) -> FmtResult {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
         write!(f, "{:?}", self)
     }
 }
 
 impl Error for MainError {}
 
-const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
-const RETRY_WAIT_BASE: Duration = Duration::new(0, 100_000_000); // 0.1 seconds
+// written at a time while decoding a downloaded object
+const DECODE_BUF_LEN: usize = 1024 * 1024;
+const MIRROR_SWITCH_RETRIES: u32 = 2;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_SUMMARY_FILES: usize = 10;
+// 0.1 seconds
+const RETRY_WAIT_BASE: Duration = Duration::new(0, 100_000_000);
+// about 100 seconds
+const RETRY_WAIT_MAX_EXPONENT: u32 = 10;
+// in the output directory, listing the files completed so far
+const STATE_FILE_NAME: &str = ".fastdl-state.json";
+// in the output directory, the index of the last successful run
+const PREVIOUS_INDEX_FILE_NAME: &str = ".fastdl-index";
 
 #[tokio::main]
 pub async fn try_main<I, T>(itr: I) -> Result<i32, Box<dyn Error>>
@@ -100,42 +377,114 @@ where
 {
     let opts = Opts::parse_from(itr);
 
-    let mut client_builder =
-        Client::builder().user_agent("PmangDownloader_27cf2b254140ab9a07a7b8615e18d902c0a26edc");
+    if let Some(log_file) = &opts.log_file {
+        if let Err(e) = open_log_file(log_file) {
+            eprintln!("Could not open log file {}: {}", log_file, e);
+            return Ok(1);
+        }
+    }
+
+    let scheme = if opts.https { "https" } else { "http" };
+    let preset_url = |url: &str| Url::parse(&format!("{}://{}", scheme, url));
+    let base_url = match &opts.base_url {
+        Some(url) => url.clone(),
+        None => preset_url(opts.region.base_url())?,
+    };
+    let install_ini_url = match &opts.install_ini_url {
+        Some(url) => url.clone(),
+        None => preset_url(opts.region.install_ini_url())?,
+    };
+
+    let uses_tls = base_url.scheme() == "https" || install_ini_url.scheme() == "https";
+    if opts.insecure && !uses_tls {
+        log!("--insecure only applies to https URLs, pass --https or an https --base-url");
+        return Ok(1);
+    }
+
+    let mut client_builder = Client::builder().user_agent(opts.user_agent.as_str());
+
+    // objects can come from any of these, the index only from the first
+    let mut bases = vec![base_url.clone()];
+    for mirror in opts.mirror.iter() {
+        let mut url = base_url.clone();
+        if url.set_host(Some(mirror)).is_err() {
+            log!("Invalid mirror {}", mirror);
+            return Ok(1);
+        }
+        bases.push(url);
+    }
+    let mirrors = Arc::new(Mirrors::new(bases));
 
     if !opts.system_dns {
-        eprintln!("Finding real IP of la.cdn.gameon.jp");
-        let mut group = NameServerConfigGroup::cloudflare_https();
-        group.merge(NameServerConfigGroup::google_https());
+        let group = if opts.doh_server.is_empty() {
+            let mut group = NameServerConfigGroup::cloudflare_https();
+            group.merge(NameServerConfigGroup::google_https());
+            group
+        } else {
+            match doh_server_group(&opts.doh_server) {
+                Ok(group) => group,
+                Err(e) => {
+                    log!("{}", e);
+                    return Ok(1);
+                }
+            }
+        };
         let resolver = AsyncResolver::tokio(
             ResolverConfig::from_parts(None, vec![], group),
             ResolverOpts::default(),
         )
         .map_err(|_| MainError::DohFail)?;
-        let responses = resolver
-            .ipv4_lookup("la.cdn.gameon.jp")
-            .await
-            .map_err(|_| MainError::DohFail)?;
-        let mut found = false;
-        for response in responses.iter() {
-            let cdn_ip = response;
-            eprintln!("la.cdn.gameon.jp is at {}", cdn_ip);
-            let cdn_addr = std::net::SocketAddrV4::new(*cdn_ip, 80);
-            client_builder = client_builder.resolve("la.cdn.gameon.jp", cdn_addr.into());
-            found = true;
-            break;
-        }
-        if !found {
-            eprintln!("DNS resolution failed");
-            return Ok(1);
+        for host in mirrors.hosts() {
+            log!("Finding real IP of {}", host);
+            // fall back to the other family when the preferred one has no address
+            let cdn_ip = if opts.prefer_ipv6 {
+                match lookup_ipv6(&resolver, host).await {
+                    Some(ip) => Some(ip),
+                    None => lookup_ipv4(&resolver, host).await,
+                }
+            } else {
+                match lookup_ipv4(&resolver, host).await {
+                    Some(ip) => Some(ip),
+                    None => lookup_ipv6(&resolver, host).await,
+                }
+            };
+            if let Some(cdn_ip) = cdn_ip {
+                log!("{} is at {}", host, cdn_ip);
+                let port = base_url.port_or_known_default().unwrap_or(80);
+                let cdn_addr = SocketAddr::new(cdn_ip, port);
+                client_builder = client_builder.resolve(host, cdn_addr);
+            } else {
+                log!("DNS resolution failed for {}", host);
+                return Ok(1);
+            }
         }
     }
 
+    if let Some(proxy) = &opts.proxy {
+        log!("Using proxy {}", proxy);
+        client_builder = client_builder.proxy(Proxy::all(proxy.as_str())?);
+    }
+
+    if opts.insecure {
+        log!("Warning: not verifying TLS certificates");
+        client_builder = client_builder.danger_accept_invalid_certs(true);
+    }
+
+    if let Some(timeout) = opts.connect_timeout {
+        client_builder = client_builder.connect_timeout(Duration::from_secs(timeout));
+    }
+    if let Some(timeout) = opts.request_timeout {
+        client_builder = client_builder.timeout(Duration::from_secs(timeout));
+    }
+    if let Some(max_idle) = opts.pool_max_idle {
+        client_builder = client_builder.pool_max_idle_per_host(max_idle);
+    }
+
     let client = client_builder.build()?;
 
-    eprintln!("Downloading install.ini");
+    log!("Downloading install.ini");
     let install_ini_str = client
-        .get("http://games.cdn.gameon.jp/lostark/install.ini")
+        .get(install_ini_url.clone())
         .send()
         .await?
         .text()
@@ -146,9 +495,9 @@ where
         .get_from(Some("VERSION"), "INDEX")
         .ok_or(MainError::InvalidVersionIni)?;
 
-    eprintln!("Downloading version.ini");
+    log!("Downloading version.ini");
     let version_ini_str = client
-        .get("http://games.cdn.gameon.jp/lostark/version.ini")
+        .get(install_ini_url.join("version.ini")?)
         .send()
         .await?
         .text()
@@ -161,32 +510,45 @@ where
 
     let index_name_str = std::cmp::max(install_ini_index_name_str, version_ini_index_name_str);
 
-    eprintln!("Current version is {}", index_name_str);
+    log!("Current version is {}", index_name_str);
 
     if index_name_str.is_empty() {
-        eprintln!("Invalid VERSION.INDEX in install.ini");
+        log!("Invalid VERSION.INDEX in install.ini");
         return Ok(2);
     }
 
-    eprintln!("Downloading index");
+    log!("Downloading index");
     let index_bytes = client
-        .get(format!(
-            "http://la.cdn.gameon.jp/la/patch/{}",
-            &index_name_str
-        ))
+        .get(format!("{}/{}", mirrors.base(0), &index_name_str))
         .send()
         .await?
         .bytes()
         .await?;
-    let index = git_index::parse(&index_bytes).ok_or(MainError::InvalidGitIndex)?;
+    if let Some(expected) = &opts.index_sha256 {
+        let actual: String = Sha256::digest(&index_bytes)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        if actual != *expected {
+            log!(
+                "Index {} has SHA-256 {}, expected {}",
+                index_name_str,
+                actual,
+                expected
+            );
+            return Ok(10);
+        }
+        log!("Index matches its SHA-256");
+    }
+    let index = git_index::parse(&index_bytes).map_err(MainError::InvalidGitIndex)?;
 
-    eprintln!("Index defines {} files", index.entries.len());
+    log!("Index defines {} files", index.entries.len());
 
     let entries: Vec<FinalFile> = index
         .entries
         .iter()
         .filter_map(|e| {
-            let s = std::str::from_utf8(e.name).ok()?;
+            let s = std::str::from_utf8(&e.name).ok()?;
             Some(FinalFile {
                 hash: e.header.sha1,
                 name: s.to_string(),
@@ -196,25 +558,25 @@ where
         .collect();
 
     if entries.len() != index.entries.len() {
-        eprintln!(
+        log!(
             "{} files had invalid filenames",
             index.entries.len() - entries.len()
         );
         return Ok(3);
     }
 
-    eprintln!("Calculating directories");
+    log!("Calculating directories");
 
-    let dirs: BTreeSet<String> = entries
-        .iter()
-        .map(|e| &e.name)
-        .filter_map(|n| Some(n[..n.rfind('/')?].into()))
+    // every name was checked to be UTF-8 above
+    let dirs: BTreeSet<&str> = git_index::directories(&index)
+        .into_iter()
+        .filter_map(|d| std::str::from_utf8(d).ok())
         .collect();
 
-    eprintln!("Found {} directories:", dirs.len());
+    log!("Found {} directories:", dirs.len());
 
     for d in dirs.iter() {
-        eprintln!("    {}", d);
+        log!("    {}", d);
     }
 
     let mut out_dir = opts.output_dir;
@@ -222,50 +584,157 @@ where
         out_dir = get_fallback_output_dir();
     }
     if out_dir.is_empty() {
-        eprintln!("Run the official installer at least once, or specify --output-dir");
+        log!("Run the official installer at least once, or specify --output-dir");
         return Ok(4);
     }
 
-    eprintln!("Will download to {}", &out_dir);
+    log!("Will download to {}", &out_dir);
 
     let out_path = Path::new(&out_dir);
 
-    eprintln!("Creating directories");
-    for d in dirs.iter() {
-        let p = out_path.join(d);
-        eprintln!("    {}", p.to_string_lossy());
-        create_dir_all(&p)?;
+    if opts.verify_only {
+        log!("Verifying {} files", entries.len());
+        let mut ok = 0usize;
+        let mut missing = 0usize;
+        let mut corrupt = 0usize;
+        for e in entries.iter() {
+            let path = out_path.join(&e.name);
+            match metadata(&path) {
+                Ok(m) if m.len() == e.size => {
+                    let verified =
+                        File::open(&path).and_then(|f| git_object::verify_sync(&e.hash, e.size, f));
+                    match verified {
+                        Ok(true) => ok += 1,
+                        Ok(false) => {
+                            log!("Corrupt: {}", &e.name);
+                            corrupt += 1;
+                        }
+                        Err(err) => {
+                            log!("Could not verify {}: {}", &e.name, err);
+                            corrupt += 1;
+                        }
+                    }
+                }
+                Ok(m) => {
+                    log!(
+                        "Corrupt: {} is {} bytes, expected {}",
+                        &e.name,
+                        m.len(),
+                        e.size
+                    );
+                    corrupt += 1;
+                }
+                Err(_) => {
+                    log!("Missing: {}", &e.name);
+                    missing += 1;
+                }
+            }
+        }
+        log!("{} OK, {} missing, {} corrupt", ok, missing, corrupt);
+        return Ok(if missing + corrupt == 0 { 0 } else { 9 });
     }
 
-    eprintln!("Checking for already completed files");
+    let previous_index_path = match &opts.previous_index {
+        Some(path) => PathBuf::from(path),
+        None => out_path.join(PREVIOUS_INDEX_FILE_NAME),
+    };
+    let previous_index = match File::open(&previous_index_path).and_then(git_index::parse_reader) {
+        Ok(previous_index) => Some(previous_index),
+        // there is no saved index before the first run
+        Err(e) if opts.previous_index.is_none() && e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            log!(
+                "Could not read previous index {}: {}",
+                previous_index_path.display(),
+                e
+            );
+            return Ok(1);
+        }
+    };
+    // files that were installed but are no longer in the index
+    let mut removed = Vec::<String>::new();
+    if let Some(previous_index) = &previous_index {
+        log!(
+            "Comparing with previous index of {} files",
+            previous_index.entries.len()
+        );
+        for e in previous_index.entries.iter() {
+            if index.find(&e.name).is_none() {
+                let name = String::from_utf8_lossy(&e.name).into_owned();
+                log!("    removed: {}", &name);
+                removed.push(name);
+            }
+        }
+        log!("{} files were removed", removed.len());
+    }
+
+    let state_path = out_path.join(STATE_FILE_NAME);
+    let verify_existing = opts.verify_existing;
+    // only trusted when it is for this index, since a new one can change any file
+    let state = if verify_existing {
+        Default::default()
+    } else {
+        load_state(&state_path, index_name_str)
+    };
+    if !state.is_empty() {
+        log!("{} files were completed by an earlier run", state.len());
+    }
+    if verify_existing {
+        log!("Checking and hashing already completed files");
+    } else {
+        log!("Checking for already completed files");
+    }
+    // files that don't need downloading, for the manifest
+    let mut done_entries = Vec::<(FinalFile, FileStatus)>::new();
     let todo_entries: Vec<FinalFile> = entries
         .into_iter()
         .filter_map(|e| {
-            if let Ok(m) = metadata(out_path.join(&e.name)) {
-                if m.len() == e.size {
-                    return None;
+            if state.get(&e.name) == Some(&hash_hex(&e.hash)) {
+                done_entries.push((e, FileStatus::Skipped));
+                return None;
+            }
+            let path = out_path.join(&e.name);
+            if let Ok(m) = metadata(&path) {
+                // the size can stay the same while the content changes
+                let changed = previous_index
+                    .as_ref()
+                    .and_then(|p| p.find(e.name.as_bytes()))
+                    .is_some_and(|p| p.header.sha1 != e.hash);
+                if m.len() == e.size && !changed {
+                    if !verify_existing {
+                        done_entries.push((e, FileStatus::Skipped));
+                        return None;
+                    }
+                    let verified =
+                        File::open(&path).and_then(|f| git_object::verify_sync(&e.hash, e.size, f));
+                    match verified {
+                        Ok(true) => {
+                            done_entries.push((e, FileStatus::Verified));
+                            return None;
+                        }
+                        Ok(false) => log!("{} does not match its hash", &e.name),
+                        Err(err) => log!("Could not verify {}: {}", &e.name, err),
+                    }
                 }
             }
             Some(e)
         })
         .collect();
 
-    eprintln!("{} files left to download", todo_entries.len());
+    log!("{} files left to download", todo_entries.len());
 
     let net_sem = Arc::new(Semaphore::new(opts.network_threads));
 
-    eprintln!("Downloading object information");
+    log!("Downloading object information");
     let content_length_tasks = todo_entries
         .iter()
         .map(|e| {
             let sem = net_sem.clone();
-            let url = url_for_hash(&e.hash);
-            let req = client.head(url);
+            let url = url_for_hash(mirrors.base(mirrors.best()), &e.hash);
+            let client = client.clone();
             tokio::spawn(async move {
                 let _permit = sem.acquire_owned().await.unwrap();
-                let res_result = req.send().await;
-                drop(_permit);
-                res_result
+                probe_content_length(&client, &url).await
             })
         })
         .collect::<Vec<_>>();
@@ -273,19 +742,18 @@ where
     let mut total_content_length = 0u64;
     let mut content_lengths = Vec::<u64>::new();
     content_lengths.reserve_exact(todo_entries.len());
-    for (e, t) in zip(todo_entries.iter(), content_length_tasks.into_iter()) {
-        let res = t.await??;
-        match res.headers().get(CONTENT_LENGTH) {
-            Some(s) => {
-                let content_length = s.to_str()?.parse()?;
+    for (e, t) in zip(todo_entries.iter(), content_length_tasks) {
+        let (content_length, res) = t.await??;
+        match content_length {
+            Some(content_length) => {
                 total_content_length += content_length;
                 content_lengths.push(content_length);
             }
             None => {
-                eprintln!(
-                    "Could not get content length of {} ({:x}) {:?}",
+                log!(
+                    "Could not get content length of {} ({}) {:?}",
                     e.name,
-                    GenericArray::from(e.hash),
+                    git_index::hash_to_hex(&e.hash),
                     res
                 );
                 return Ok(5);
@@ -293,125 +761,403 @@ where
         }
     }
 
-    eprintln!(
+    log!(
         "Total of {:.3} GiB to download",
         (total_content_length as f64) / 1024. / 1024. / 1024.
     );
 
-    let disk_sem = Arc::new(Semaphore::new(opts.disk_threads));
+    if opts.dry_run {
+        // name, decoded size and download size of each file, on stdout so runs can be diffed
+        let mut total_size = 0u64;
+        for (e, l) in zip(todo_entries.iter(), content_lengths.iter()) {
+            println!("{}\t{}\t{}", e.name, e.size, l);
+            total_size += e.size;
+        }
+        println!(
+            "{} files\t{}\t{}",
+            todo_entries.len(),
+            total_size,
+            total_content_length
+        );
+        return Ok(0);
+    }
 
-    let mut total_chunks = 0u64;
-    let file_tasks = zip(todo_entries.iter(), content_lengths.iter())
-        .map(|(e, l)| {
-            let len = *l;
-            let name = e.name.clone();
-            let dst_path = out_path.join(&name);
-            let tmp_path = out_path.join(format!("{}.tmp", &name));
-            let url = url_for_hash(&e.hash);
-
-            let total_file_chunks = (len + CHUNK_SIZE - 1) / CHUNK_SIZE;
-            total_chunks += total_file_chunks;
-            let mut chunk_tasks = Vec::<JoinHandle<Result<(), TaskError>>>::new();
-            for chunk_i in 0u64..total_file_chunks {
-                let client_ref = client.clone();
-                let name_clone = name.clone();
-                let sem = net_sem.clone();
-                let url_clone = url.clone();
-
-                let range_begin = chunk_i * CHUNK_SIZE;
-                let range_end = min(len, (chunk_i + 1u64) * CHUNK_SIZE);
-                let range_size = range_end - range_begin;
-                let range_str = format!("bytes={}-{}", range_begin, range_end - 1);
-                let req = client_ref.get(url_clone.clone()).header(RANGE, range_str.clone()).build().unwrap(); // TODO: eliminate unwrap
-                let tmp_path_clone = tmp_path.clone();
-                let task = tokio::spawn(async move {
-                    // first take the semaphore so that we don't open files before we're ready
-                    let _permit = sem.acquire_owned().await.unwrap();
-                    // now acquire mmap
-                    // TODO: make the conversion from u64 to usize nicer
-                    let mut mapping = create_mmap(tmp_path_clone, len, range_begin, range_size as usize).map_err(TaskError::Io)?;
-                    let mut retry = 0;
-                    loop {
-                        // send request and wait for response
-                        let res_result = client_ref.execute(req.try_clone().unwrap()).await; // TODO: eliminate unwrap
-                        // verify result
-                        match res_result {
-                            Ok(res) => {
-                                if res.status() != 206 {
-                                    let delay = RETRY_WAIT_BASE * 2u32.pow(retry);
-                                    eprintln!(
-                                        "Error downloading {} ({}) chunk {} ({}) (retry {}) wait {:?}: {}",
-                                        &name_clone, &url_clone, chunk_i, &range_str, retry, &delay, res.status()
-                                    );
-                                    tokio::time::sleep(delay).await;
-                                    retry += 1;
-                                    continue;
-                                }
-                                let bytes = res.bytes().await.map_err(TaskError::Request)?;
-                                mapping.copy_from_slice(bytes.as_ref());
-                                mapping.flush_async().map_err(TaskError::Io)?;
-                                break;
-                            }
-                            Err(e) => {
-                                let delay = RETRY_WAIT_BASE * 2u32.pow(retry);
-                                eprintln!(
-                                    "Error downloading {} ({}) chunk {} ({}) (retry {}) wait {:?}: {:?}",
-                                    &name_clone, &url_clone, chunk_i, &range_str, retry, &delay, e
-                                );
-                                tokio::time::sleep(delay).await;
-                                retry += 1;
-                            }
-                        }
-                    }
-                    // allow another task to request
-                    drop(_permit);
-                    Ok(())
-                });
-                chunk_tasks.push(task);
+    if opts.delete_removed {
+        for name in removed.iter() {
+            let path = out_path.join(name);
+            match remove_file(&path) {
+                Ok(()) => log!("Deleted {}", path.display()),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => log!("Could not delete {}: {}", path.display(), e),
             }
+        }
+    }
+
+    if !opts.ignore_space {
+        // each .tmp is still there while its final file is written
+        let needed = total_content_length + todo_entries.iter().map(|e| e.size).sum::<u64>();
+        // the output directory itself may not have been created yet
+        let existing = out_path
+            .ancestors()
+            .find(|p| p.exists())
+            .unwrap_or_else(|| Path::new("."));
+        let available = available_space(existing)?;
+        log!(
+            "Need up to {:.3} GiB of disk space, {:.3} GiB available",
+            (needed as f64) / 1024. / 1024. / 1024.,
+            (available as f64) / 1024. / 1024. / 1024.
+        );
+        if needed > available {
+            log!("Not enough disk space, free some up or pass --ignore-space");
+            return Ok(8);
+        }
+    }
+
+    log!("Creating directories");
+    for d in dirs.iter() {
+        let p = out_path.join(d);
+        log!("    {}", p.to_string_lossy());
+        create_dir_all(&p)?;
+    }
+
+    let chunk_size = opts.chunk_size;
+    if let Some(largest) = content_lengths.iter().max() {
+        if chunk_size > *largest {
+            log!(
+                "Chunk size of {:.3} MiB is larger than any file, so every file will be one chunk",
+                (chunk_size as f64) / 1024. / 1024.
+            );
+        }
+    }
 
-            // TODO: task to decode the git object
-            {
-                let disk_sem_clone = disk_sem.clone();
+    let rate_limiter = if opts.max_rate == 0 {
+        None
+    } else {
+        Some(RateLimiter::new(opts.max_rate))
+    };
+
+    // compressed bytes downloaded so far, counting chunks already there from an earlier run
+    let downloaded = Arc::new(AtomicU64::new(0));
+
+    // Start the biggest files first, so they overlap with the long tail of small ones instead of
+    // being left to download on their own at the end
+    let (todo_entries, content_lengths): (Vec<FinalFile>, Vec<u64>) = {
+        let mut todo: Vec<_> = zip(todo_entries, content_lengths).collect();
+        todo.sort_by_key(|(_, l)| Reverse(*l));
+        todo.into_iter().unzip()
+    };
+
+    let json_progress = opts.progress_format == ProgressFormat::Json;
+
+    // set by each file's disk task once the final file is in place
+    let files_done: Arc<Vec<AtomicBool>> = Arc::new(
+        todo_entries
+            .iter()
+            .map(|_| AtomicBool::new(false))
+            .collect(),
+    );
 
-                let task = tokio::spawn(async move {
-                    for t in chunk_tasks.into_iter() {
-                        t.await.map_err(TaskError::Join)??;
+    // On Ctrl-C, stop starting chunks but let the ones in flight finish so their marks are right.
+    // A second one stops without waiting for them, after saving which files are done.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        let files_done = files_done.clone();
+        let state_path = state_path.clone();
+        let index_name = index_name_str.to_string();
+        let state = state.clone();
+        let done_entries = done_entries.clone();
+        let todo_entries = todo_entries.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            log!("Interrupted, finishing chunks in progress. Press Ctrl-C again to stop now.");
+            interrupted.store(true, Ordering::Relaxed);
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            let statuses: Vec<FileStatus> = files_done
+                .iter()
+                .map(|done| {
+                    if done.load(Ordering::Relaxed) {
+                        FileStatus::Downloaded
+                    } else {
+                        FileStatus::Incomplete
                     }
-                    eprintln!("Download complete for {}. Waiting for disk thread", &name);
-
-                    tokio::task::spawn_blocking(move || {
-                        let _permit = disk_sem_clone.acquire_owned();
-                        eprintln!("Decompression started for {}", &name);
-
-                        let mut dst_f = File::create(dst_path)?;
-                        let tmp_f = File::open(tmp_path.clone())?;
-                        let mut decode_read = git_object::decode_sync(tmp_f);
-                        copy(&mut decode_read, &mut dst_f)?;
-                        dst_f.flush()?;
-                        eprintln!("Decompression done for {}", &name);
-                        // close and delete temp file
-                        drop(decode_read);
-                        remove_file(tmp_path)?;
-                        Ok(())
-                    }).await.map_err(TaskError::Join)?.map_err(TaskError::Io)?;
-
-                    Ok::<(), TaskError>(())
-                });
-                task
+                })
+                .collect();
+            let saved = write_state(
+                &state_path,
+                &index_name,
+                &state,
+                &done_entries,
+                &todo_entries,
+                &statuses,
+            );
+            if let Err(e) = saved {
+                log!("Could not save state to {}: {}", state_path.display(), e);
+            }
+            let incomplete = statuses
+                .iter()
+                .filter(|&&status| status == FileStatus::Incomplete)
+                .count();
+            log!(
+                "Interrupted again, stopping with {} files left incomplete",
+                incomplete
+            );
+            std::process::exit(130);
+        });
+    }
+
+    let concurrency = if opts.auto_concurrency {
+        let start = min(auto_concurrency::START_PERMITS, opts.network_threads);
+        // every object information request has finished by now, so these are all free
+        net_sem
+            .clone()
+            .acquire_many_owned((opts.network_threads - start) as u32)
+            .await?
+            .forget();
+        log!(
+            "Starting with network concurrency of {}, up to {}",
+            start,
+            opts.network_threads
+        );
+        Some(tokio::spawn(auto_concurrency::run(
+            net_sem.clone(),
+            downloaded.clone(),
+            start,
+            opts.network_threads,
+        )))
+    } else {
+        None
+    };
+
+    // retries of all of a file's chunks, for the summary at the end
+    let retry_counts: Vec<Arc<AtomicU64>> = todo_entries
+        .iter()
+        .map(|_| Arc::new(AtomicU64::new(0)))
+        .collect();
+
+    let ctx = Arc::new(DownloadContext {
+        client,
+        mirrors: mirrors.clone(),
+        net_sem: net_sem.clone(),
+        disk_sem: Arc::new(Semaphore::new(opts.disk_threads)),
+        files_sem: Arc::new(Semaphore::new(opts.max_open_files)),
+        rate_limiter,
+        downloaded: downloaded.clone(),
+        interrupted: interrupted.clone(),
+        files_done: files_done.clone(),
+        chunk_size,
+        chunk_timeout: opts.chunk_timeout.map(Duration::from_secs),
+        max_retries: opts.max_retries,
+        repair_attempts: opts.repair_attempts,
+        json_progress,
+        total_content_length,
+    });
+
+    let download_start = Instant::now();
+    let file_tasks = zip(todo_entries.iter(), content_lengths.iter())
+        .enumerate()
+        .map(|(file_i, (e, &len))| {
+            let tmp_path = out_path.join(format!("{}.tmp", &e.name));
+            let marks_path = out_path.join(format!("{}.tmp.chunks", &e.name));
+
+            let total_file_chunks = len.div_ceil(chunk_size);
+            // worth decoding before downloading it all again
+            let unmarked_tmp = is_unmarked_tmp(&tmp_path, &marks_path, len);
+            let done_chunks = if unmarked_tmp {
+                log!(
+                    "Found {} at its full size, checking it before downloading",
+                    tmp_path.display()
+                );
+                vec![true; total_file_chunks as usize]
+            } else {
+                load_chunk_marks(&tmp_path, &marks_path, len, chunk_size)
+            };
+            let done_chunk_count = done_chunks.iter().filter(|&&x| x).count();
+            if done_chunk_count > 0 && !unmarked_tmp {
+                log!(
+                    "Resuming {} with {} of {} chunks already downloaded",
+                    &e.name,
+                    done_chunk_count,
+                    total_file_chunks
+                );
             }
+            let job = Arc::new(FileJob {
+                ctx: ctx.clone(),
+                file_i,
+                name: e.name.clone(),
+                hash: e.hash,
+                size: e.size,
+                len,
+                dst_path: out_path.join(&e.name),
+                tmp_path,
+                marks_path,
+                decoded_path: out_path.join(format!("{}.tmp.decoded", &e.name)),
+                file_permit: OnceCell::new(),
+                started: AtomicBool::new(false),
+                retry_count: retry_counts[file_i].clone(),
+            });
+            let mut chunk_tasks = Vec::new();
+            for chunk_i in 0u64..total_file_chunks {
+                if done_chunks[chunk_i as usize] {
+                    let range_size = min(len, (chunk_i + 1u64) * chunk_size) - chunk_i * chunk_size;
+                    downloaded.fetch_add(range_size, Ordering::Relaxed);
+                    continue;
+                }
+                chunk_tasks.push(spawn_chunk(job.clone(), chunk_i));
+            }
+            tokio::spawn(finish_file(job, chunk_tasks, unmarked_tmp))
         })
         .collect::<Vec<_>>();
+    // counted as soon as the tasks are made, so not part of the speed
+    let resumed_bytes = downloaded.load(Ordering::Relaxed);
+
+    let progress_task = if opts.quiet {
+        None
+    } else {
+        let downloaded = downloaded.clone();
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+            // the first tick is immediate
+            interval.tick().await;
+            let mut last = downloaded.load(Ordering::Relaxed);
+            loop {
+                interval.tick().await;
+                let now = downloaded.load(Ordering::Relaxed);
+                let percent = if total_content_length == 0 {
+                    100.
+                } else {
+                    (now as f64) * 100. / (total_content_length as f64)
+                };
+                log!(
+                    "Downloaded {:.3} of {:.3} GiB ({:.1}%) at {:.2} MiB/s",
+                    (now as f64) / 1024. / 1024. / 1024.,
+                    (total_content_length as f64) / 1024. / 1024. / 1024.,
+                    percent,
+                    (now.saturating_sub(last) as f64)
+                        / 1024.
+                        / 1024.
+                        / PROGRESS_INTERVAL.as_secs_f64()
+                );
+                last = now;
+            }
+        }))
+    };
 
-    for (t, entry) in zip(file_tasks.into_iter(), todo_entries.iter()) {
+    let mut incomplete = 0usize;
+    let mut statuses = vec![FileStatus::Incomplete; todo_entries.len()];
+    for (i, (t, entry)) in zip(file_tasks, todo_entries.iter()).enumerate() {
         let result = t.await?;
+        if result.is_err() && interrupted.load(Ordering::Relaxed) {
+            // keep waiting on the rest so they can finish cleanly
+            incomplete += 1;
+            continue;
+        }
         if let Err(e) = result {
-            eprintln!("Error processing {} {}", entry.name, e);
+            log!("Error processing {} {}", entry.name, e);
+            statuses[i] = FileStatus::Failed;
+            print_retries(&todo_entries, &retry_counts);
+            print_summary(
+                &statuses,
+                done_entries.len(),
+                downloaded
+                    .load(Ordering::Relaxed)
+                    .saturating_sub(resumed_bytes),
+                download_start.elapsed(),
+            );
+            write_state(
+                &state_path,
+                index_name_str,
+                &state,
+                &done_entries,
+                &todo_entries,
+                &statuses,
+            )?;
+            if let Some(manifest) = &opts.manifest {
+                write_manifest(manifest, &done_entries, &todo_entries, &statuses)?;
+            }
+            if json_progress {
+                println!(
+                    "{}",
+                    json!({
+                        "event": "summary",
+                        "ok": false,
+                        "failed": &entry.name,
+                        "downloaded": downloaded.load(Ordering::Relaxed),
+                        "total": total_content_length,
+                    })
+                );
+            }
             return Ok(6);
         }
+        statuses[i] = FileStatus::Downloaded;
     }
 
-    eprintln!("All done!");
+    print_retries(&todo_entries, &retry_counts);
+    print_summary(
+        &statuses,
+        done_entries.len(),
+        downloaded
+            .load(Ordering::Relaxed)
+            .saturating_sub(resumed_bytes),
+        download_start.elapsed(),
+    );
+    write_state(
+        &state_path,
+        index_name_str,
+        &state,
+        &done_entries,
+        &todo_entries,
+        &statuses,
+    )?;
+    if let Some(manifest) = &opts.manifest {
+        write_manifest(manifest, &done_entries, &todo_entries, &statuses)?;
+    }
+
+    if interrupted.load(Ordering::Relaxed) {
+        log!("Stopped with {} files left incomplete", incomplete);
+        if json_progress {
+            println!(
+                "{}",
+                json!({
+                    "event": "summary",
+                    "ok": false,
+                    "incomplete": incomplete,
+                    "downloaded": downloaded.load(Ordering::Relaxed),
+                    "total": total_content_length,
+                })
+            );
+        }
+        return Ok(7);
+    }
+
+    if json_progress {
+        println!(
+            "{}",
+            json!({
+                "event": "summary",
+                "ok": true,
+                "files": todo_entries.len(),
+                "downloaded": downloaded.load(Ordering::Relaxed),
+                "total": total_content_length,
+            })
+        );
+    }
+
+    if let Some(t) = progress_task {
+        t.abort();
+    }
+    if let Some(t) = concurrency {
+        t.abort();
+    }
+
+    // so the next run can tell which files this one changed
+    save_index(&out_path.join(PREVIOUS_INDEX_FILE_NAME), &index_bytes)?;
+
+    log!("All done!");
 
     Ok(0)
 }
@@ -432,12 +1178,674 @@ fn get_fallback_output_dir() -> String {
     .unwrap_or("".into())
 }
 
-fn url_for_hash<'a>(hash: &Hash) -> String {
-    let hash_str = format!("{:x}", GenericArray::<u8, U20>::from_slice(hash));
-    let url = format!(
-        "http://la.cdn.gameon.jp/la/patch/objects/{}/{}",
-        &hash_str[..2],
-        &hash_str[2..]
+// Also used by the disk task to download the whole file again after a hash mismatch
+fn spawn_chunk(job: Arc<FileJob>, chunk_i: u64) -> JoinHandle<Result<(), TaskError>> {
+    tokio::spawn(async move { download_chunk(&job, chunk_i).await })
+}
+
+async fn download_chunk(job: &FileJob, chunk_i: u64) -> Result<(), TaskError> {
+    let ctx = &job.ctx;
+    let range_begin = chunk_i * ctx.chunk_size;
+    let range_end = min(job.len, (chunk_i + 1u64) * ctx.chunk_size);
+    let range_size = range_end - range_begin;
+    let range_str = format!("bytes={}-{}", range_begin, range_end - 1);
+
+    take_file_permit(job).await?;
+    // first take the semaphore so that we don't open files before we're ready
+    let permit = ctx.net_sem.clone().acquire_owned().await.unwrap();
+    if ctx.interrupted.load(Ordering::Relaxed) {
+        return Err(TaskError::Interrupted);
+    }
+    if ctx.json_progress && !job.started.swap(true, Ordering::Relaxed) {
+        println!("{}", json!({"event": "file_start", "name": &job.name}));
+    }
+    // now acquire mmap
+    // TODO: make the conversion from u64 to usize nicer
+    let mut writer = create_chunk_writer(&job.tmp_path, job.len, range_begin, range_size as usize)
+        .map_err(TaskError::Io)?;
+    let mut retry = 0;
+    let mut mirror_i = ctx.mirrors.best();
+    let mut mirror_retry = 0;
+    loop {
+        if ctx.interrupted.load(Ordering::Relaxed) {
+            return Err(TaskError::Interrupted);
+        }
+        // send request and wait for response
+        let url = url_for_hash(ctx.mirrors.base(mirror_i), &job.hash);
+        let deadline = ctx.chunk_timeout.map(|t| TokioInstant::now() + t);
+        let res_result = with_deadline(
+            deadline,
+            ctx.client
+                .get(&url)
+                .header(RANGE, range_str.as_str())
+                .send(),
+        )
+        .await;
+        // verify result
+        let failure = match res_result {
+            // the body can time out too, which is worth retrying like any other failure
+            Ok(res) if res.status() == 206 => {
+                let body = read_body(res, range_size as usize, ctx.rate_limiter.as_ref());
+                match with_deadline(deadline, body).await {
+                    Ok(bytes) => {
+                        let now_downloaded = ctx
+                            .downloaded
+                            .fetch_add(bytes.len() as u64, Ordering::Relaxed)
+                            + bytes.len() as u64;
+                        if ctx.json_progress {
+                            println!(
+                                "{}",
+                                json!({
+                                    "event": "chunk_done",
+                                    "name": &job.name,
+                                    "chunk": chunk_i,
+                                    "downloaded": now_downloaded,
+                                    "total": ctx.total_content_length,
+                                })
+                            );
+                        }
+                        writer.write_chunk(&bytes).map_err(TaskError::Io)?;
+                        writer.flush().map_err(TaskError::Io)?;
+                        mark_chunk_done(&job.marks_path, job.len, ctx.chunk_size, chunk_i)
+                            .map_err(TaskError::Io)?;
+                        break;
+                    }
+                    Err(e) => {
+                        log!(
+                            "Error downloading {} ({}) chunk {} ({}) (retry {}): {:?}",
+                            &job.name,
+                            &url,
+                            chunk_i,
+                            &range_str,
+                            retry,
+                            e
+                        );
+                        e
+                    }
+                }
+            }
+            // Objects are named by their hash so shouldn't ever change length, but a server can
+            // still get it wrong. Only a length that stays different is worth giving up over,
+            // since planning the chunks again would just download an object with the wrong hash.
+            Ok(res) if res.status() == StatusCode::RANGE_NOT_SATISFIABLE => {
+                let content_range = res
+                    .headers()
+                    .get(CONTENT_RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("none");
+                log!(
+                    "Range {} of {} ({}) not satisfiable (retry {}), server has Content-Range {}",
+                    &range_str,
+                    &job.name,
+                    &url,
+                    retry,
+                    content_range
+                );
+                if let Ok((Some(actual), _)) = probe_content_length(&ctx.client, &url).await {
+                    if actual != job.len {
+                        log!(
+                            "{} is now {} bytes rather than {}, giving up on it",
+                            &job.name,
+                            actual,
+                            job.len
+                        );
+                        return Err(TaskError::LengthChanged {
+                            planned: job.len,
+                            actual,
+                        });
+                    }
+                }
+                TaskError::Status(res.status())
+            }
+            Ok(res) => {
+                log!(
+                    "Error downloading {} ({}) chunk {} ({}) (retry {}): {}",
+                    &job.name,
+                    &url,
+                    chunk_i,
+                    &range_str,
+                    retry,
+                    res.status()
+                );
+                TaskError::Status(res.status())
+            }
+            Err(e) => {
+                log!(
+                    "Error downloading {} ({}) chunk {} ({}) (retry {}): {:?}",
+                    &job.name,
+                    &url,
+                    chunk_i,
+                    &range_str,
+                    retry,
+                    e
+                );
+                e
+            }
+        };
+        if retry >= ctx.max_retries {
+            log!(
+                "Giving up on {} chunk {} after {} retries",
+                &job.name,
+                chunk_i,
+                retry
+            );
+            return Err(failure);
+        }
+        ctx.mirrors.record_failure(mirror_i);
+        mirror_retry += 1;
+        if mirror_retry >= MIRROR_SWITCH_RETRIES {
+            mirror_i = ctx.mirrors.best_other(mirror_i);
+            mirror_retry = 0;
+        }
+        let delay = retry_delay(retry);
+        log!("Retrying {} chunk {} in {:?}", &job.name, chunk_i, &delay);
+        tokio::time::sleep(delay).await;
+        retry += 1;
+        job.retry_count.fetch_add(1, Ordering::Relaxed);
+    }
+    // allow another task to request
+    drop(permit);
+    Ok(())
+}
+
+async fn take_file_permit(job: &FileJob) -> Result<(), TaskError> {
+    job.file_permit
+        .get_or_try_init(|| job.ctx.files_sem.clone().acquire_owned())
+        .await
+        .map_err(TaskError::Acquire)?;
+    Ok(())
+}
+
+// The disk task. Waits for the file's chunks, then decodes it, downloading it all again while the
+// hash doesn't match and there are repair attempts left.
+async fn finish_file(
+    job: Arc<FileJob>,
+    mut chunk_tasks: Vec<JoinHandle<Result<(), TaskError>>>,
+    mut unmarked_tmp: bool,
+) -> Result<(), TaskError> {
+    let ctx = &job.ctx;
+    let mut repair_attempt = 0;
+    loop {
+        for t in chunk_tasks.drain(..) {
+            t.await.map_err(TaskError::Join)??;
+        }
+        // not taken yet if every chunk was already downloaded
+        take_file_permit(&job).await?;
+        log!(
+            "Download complete for {}. Waiting for disk thread",
+            &job.name
+        );
+        // a file resumed with every chunk already there never took a network permit
+        if ctx.json_progress && !job.started.swap(true, Ordering::Relaxed) {
+            println!("{}", json!({"event": "file_start", "name": &job.name}));
+        }
+
+        let decode_job = job.clone();
+        let verified =
+            spawn_blocking_with_permit(ctx.disk_sem.clone(), move || decode_file(&decode_job))
+                .await??;
+        if verified {
+            ctx.files_done[job.file_i].store(true, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        // the marks would stop the bad chunks from being downloaded again
+        let _ = remove_file(&job.marks_path);
+        if unmarked_tmp {
+            // it was never known to be complete, so this isn't a repair
+            unmarked_tmp = false;
+            log!(
+                "{} was not a complete download, downloading it again",
+                job.tmp_path.display()
+            );
+        } else if repair_attempt >= ctx.repair_attempts {
+            log!(
+                "Hash mismatch for {}, giving up after {} repair attempts, keeping {}",
+                &job.name,
+                repair_attempt,
+                job.tmp_path.display()
+            );
+            if ctx.json_progress {
+                println!(
+                    "{}",
+                    json!({"event": "file_done", "name": &job.name, "hash_ok": false})
+                );
+            }
+            return Err(TaskError::HashMismatch);
+        } else {
+            repair_attempt += 1;
+            log!(
+                "Hash mismatch for {}, downloading it again (repair attempt {} of {})",
+                &job.name,
+                repair_attempt,
+                ctx.repair_attempts
+            );
+        }
+        let _ = remove_file(&job.tmp_path);
+        // Its bytes are about to be counted again. This is the one place the count goes down, so
+        // everything taking a difference of it has to saturate.
+        ctx.downloaded.fetch_sub(job.len, Ordering::Relaxed);
+        chunk_tasks = (0u64..job.len.div_ceil(ctx.chunk_size))
+            .map(|chunk_i| spawn_chunk(job.clone(), chunk_i))
+            .collect();
+    }
+}
+
+// Runs on a disk thread. False if the file has to be downloaded again.
+fn decode_file(job: &FileJob) -> Result<bool, TaskError> {
+    log!("Decompression started for {}", &job.name);
+
+    // decoded beside the final file and only moved over it once the hash matches, so a bad object
+    // never becomes the final file
+    let tmp_f = File::open(&job.tmp_path).map_err(TaskError::Io)?;
+    let mut decoded_f = File::create(&job.decoded_path).map_err(TaskError::Io)?;
+    let verified = match decode_verified(tmp_f, &job.hash, job.size, &mut decoded_f) {
+        Ok(verified) => verified,
+        // a bad chunk is as likely to break the compressed stream as to change what it decodes to
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::InvalidData | ErrorKind::InvalidInput | ErrorKind::UnexpectedEof
+            ) =>
+        {
+            log!("Could not decode {}: {}", &job.name, e);
+            false
+        }
+        Err(e) => {
+            let _ = remove_file(&job.decoded_path);
+            return Err(TaskError::Io(e));
+        }
+    };
+    drop(decoded_f);
+    if !verified {
+        let _ = remove_file(&job.decoded_path);
+        return Ok(false);
+    }
+    rename(&job.decoded_path, &job.dst_path).map_err(TaskError::Io)?;
+    log!("Decompression done for {}", &job.name);
+    if job.ctx.json_progress {
+        println!(
+            "{}",
+            json!({"event": "file_done", "name": &job.name, "hash_ok": true})
+        );
+    }
+    // delete temp file
+    remove_file(&job.tmp_path).map_err(TaskError::Io)?;
+    // an unmarked .tmp has none
+    match remove_file(&job.marks_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(TaskError::Io(e)),
+        _ => Ok(true),
+    }
+}
+
+// Runs f on a blocking thread once sem has a permit free, holding the permit until f returns. The
+// permit has to be awaited out here, since the blocking thread can't.
+async fn spawn_blocking_with_permit<T: Send + 'static>(
+    sem: Arc<Semaphore>,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, TaskError> {
+    let permit = sem.acquire_owned().await.map_err(TaskError::Acquire)?;
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        f()
+    })
+    .await
+    .map_err(TaskError::Join)
+}
+
+// Decodes the object in src into dst while hashing it, in one pass. Whether the hash matched is
+// only known at the end, so dst has to be thrown away on false. The object's header has to
+// declare the size the index gives, so a bad one can't fill the disk, and is InvalidData if not.
+fn decode_verified(src: File, hash: &Hash, size: u64, dst: &mut File) -> Result<bool, IoError> {
+    let (decoded_size, decode_read) = git_object::decode_sync_with_size(src)?;
+    if decoded_size != size {
+        return Err(IoError::new(
+            ErrorKind::InvalidData,
+            format!("object is {} bytes, index says {}", decoded_size, size),
+        ));
+    }
+    dst.set_len(size)?;
+    let mut tee = TeeRead {
+        r: decode_read,
+        w: BufWriter::with_capacity(DECODE_BUF_LEN, dst),
+    };
+    let verified = git_object::verify_sync(hash, size, &mut tee)?;
+    tee.w.flush()?;
+    Ok(verified)
+}
+
+// Written to a temporary file first, so an interrupted save leaves the previous index.
+fn save_index(path: &Path, index_bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    let tmp_path = path.with_extension("tmp");
+    let mut f = File::create(&tmp_path)?;
+    f.write_all(index_bytes)?;
+    f.flush()?;
+    drop(f);
+    rename(tmp_path, path)?;
+    Ok(())
+}
+
+// Prints the files whose chunks were retried the most, if any were.
+fn print_retries(todo_entries: &[FinalFile], retry_counts: &[Arc<AtomicU64>]) {
+    let mut retried: Vec<(&FinalFile, u64)> = zip(todo_entries.iter(), retry_counts.iter())
+        .map(|(e, count)| (e, count.load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    if retried.is_empty() {
+        return;
+    }
+    retried.sort_by_key(|(_, count)| Reverse(*count));
+    let total: u64 = retried.iter().map(|(_, count)| count).sum();
+    log!(
+        "{} retries in {} files, most retried:",
+        total,
+        retried.len()
+    );
+    for (e, count) in retried.iter().take(RETRY_SUMMARY_FILES) {
+        log!("    {} {}", count, e.name);
+    }
+}
+
+// One line on how the download went, with the files that didn't make it if it stopped early.
+// Bytes resumed from an earlier run aren't counted, so the speed is this run's.
+fn print_summary(statuses: &[FileStatus], skipped: usize, bytes: u64, elapsed: Duration) {
+    let count = |status| statuses.iter().filter(|&&s| s == status).count();
+    let secs = elapsed.as_secs_f64();
+    let mib_per_sec = if secs > 0. {
+        (bytes as f64) / 1024. / 1024. / secs
+    } else {
+        0.
+    };
+    let mut line = format!(
+        "Downloaded {:.3} GiB for {} files in {:.1}s ({:.2} MiB/s), {} already complete",
+        (bytes as f64) / 1024. / 1024. / 1024.,
+        count(FileStatus::Downloaded),
+        secs,
+        mib_per_sec,
+        skipped
     );
+    let failed = count(FileStatus::Failed);
+    let incomplete = count(FileStatus::Incomplete);
+    if failed + incomplete > 0 {
+        line += &format!(", {} failed, {} incomplete", failed, incomplete);
+    }
+    log!("{}", line);
+}
+
+// Saves the files that are complete now and known to match their hash, whether from this run or
+// recorded in the loaded state by an earlier one. Files skipped only because their size matched
+// are left out, so the state only vouches for files whose hash was checked.
+fn write_state(
+    path: &Path,
+    index_name: &str,
+    state: &BTreeMap<String, String>,
+    done_entries: &[(FinalFile, FileStatus)],
+    todo_entries: &[FinalFile],
+    statuses: &[FileStatus],
+) -> Result<(), Box<dyn Error>> {
+    let completed = done_entries
+        .iter()
+        .filter(|(e, status)| {
+            *status == FileStatus::Verified || state.get(&e.name) == Some(&hash_hex(&e.hash))
+        })
+        .map(|(e, _)| e)
+        .chain(
+            zip(todo_entries.iter(), statuses.iter())
+                .filter(|(_, &status)| status == FileStatus::Downloaded)
+                .map(|(e, _)| e),
+        )
+        .map(|e| (e.name.as_str(), hash_hex(&e.hash)));
+    save_state(path, index_name, completed)?;
+    Ok(())
+}
+
+// Writes every file in the index with its status as a JSON array sorted by name, so manifests of
+// different runs or versions can be diffed.
+fn write_manifest(
+    path: &str,
+    done_entries: &[(FinalFile, FileStatus)],
+    todo_entries: &[FinalFile],
+    statuses: &[FileStatus],
+) -> Result<(), Box<dyn Error>> {
+    let mut files: Vec<(&FinalFile, FileStatus)> = done_entries
+        .iter()
+        .map(|(e, status)| (e, *status))
+        .chain(zip(todo_entries.iter(), statuses.iter().copied()))
+        .collect();
+    files.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+    let manifest: Vec<_> = files
+        .iter()
+        .map(|(e, status)| {
+            json!({
+                "name": &e.name,
+                "hash": hash_hex(&e.hash),
+                "size": e.size,
+                "status": status.as_str(),
+            })
+        })
+        .collect();
+    let mut f = File::create(path)?;
+    serde_json::to_writer_pretty(&mut f, &manifest)?;
+    f.write_all(b"\n")?;
+    Ok(())
+}
+
+fn doh_server_group(servers: &[String]) -> Result<NameServerConfigGroup, String> {
+    let mut group = NameServerConfigGroup::new();
+    for server in servers.iter() {
+        let url =
+            Url::parse(server).map_err(|e| format!("Invalid DoH server {}: {}", server, e))?;
+        let host = match (url.scheme(), url.host_str()) {
+            ("https", Some(host)) => host,
+            _ => {
+                return Err(format!(
+                    "Invalid DoH server {}, must be an https URL",
+                    server
+                ))
+            }
+        };
+        let port = url.port_or_known_default().unwrap_or(443);
+        // brackets are kept around IPv6 literals in URLs
+        let ips: Vec<IpAddr> = match host.trim_matches(|c| c == '[' || c == ']').parse() {
+            Ok(ip) => vec![ip],
+            Err(_) => (host, port)
+                .to_socket_addrs()
+                .map_err(|e| format!("Could not resolve DoH server {}: {}", host, e))?
+                .map(|a| a.ip())
+                .collect(),
+        };
+        group.merge(NameServerConfigGroup::from_ips_https(
+            &ips,
+            port,
+            host.to_string(),
+            true,
+        ));
+    }
+    Ok(group)
+}
+
+async fn lookup_ipv4(resolver: &TokioAsyncResolver, host: &str) -> Option<IpAddr> {
+    let responses = resolver.ipv4_lookup(host).await.ok()?;
+    let ip = responses.iter().next()?;
+    Some(IpAddr::V4(*ip))
+}
+
+async fn lookup_ipv6(resolver: &TokioAsyncResolver, host: &str) -> Option<IpAddr> {
+    let responses = resolver.ipv6_lookup(host).await.ok()?;
+    let ip = responses.iter().next()?;
+    Some(IpAddr::V6(*ip))
+}
+
+// Exponential backoff with full jitter, so chunks that failed together don't all retry together.
+fn retry_delay(retry: u32) -> Duration {
+    let max = RETRY_WAIT_BASE * 2u32.pow(min(retry, RETRY_WAIT_MAX_EXPONENT));
+    rand::thread_rng().gen_range(Duration::ZERO..=max)
+}
+
+// Parses a byte count with an optional binary suffix, taking a bare number to be in unit bytes.
+fn parse_size(s: &str, unit: u64) -> Result<u64, String> {
+    let digits_len = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, suffix) = s.split_at(digits_len);
+    let value: u64 = digits.parse().map_err(|_| format!("invalid size {}", s))?;
+    let multiplier = match suffix.to_ascii_lowercase().as_str() {
+        "" => unit,
+        "b" => 1,
+        "k" | "kb" | "kib" => 1024,
+        "m" | "mb" | "mib" => 1024 * 1024,
+        "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size suffix {}", suffix)),
+    };
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size {} is too large", s))
+}
+
+fn parse_chunk_size(s: &str) -> Result<u64, String> {
+    let size = parse_size(s, 1024 * 1024)?;
+    if size == 0 {
+        return Err("chunk size must not be zero".into());
+    }
+    Ok(size)
+}
+
+fn parse_rate(s: &str) -> Result<u64, String> {
+    parse_size(s, 1)
+}
+
+fn parse_http_url(s: &str) -> Result<Url, String> {
+    let url = Url::parse(s).map_err(|e| format!("invalid URL {}: {}", s, e))?;
+    match (url.scheme(), url.host_str()) {
+        ("http", Some(_)) | ("https", Some(_)) => Ok(url),
+        _ => Err(format!(
+            "invalid URL {}, must be http or https with a host",
+            s
+        )),
+    }
+}
+
+// as lowercase hex, the way it is compared
+fn parse_sha256(s: &str) -> Result<String, String> {
+    if s.len() != 64 || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid SHA-256 {}, must be 64 hex digits", s));
+    }
+    Ok(s.to_ascii_lowercase())
+}
+
+fn parse_user_agent(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Err("user agent must not be empty".into());
+    }
+    Ok(s.into())
+}
+
+// Reads a response body a piece at a time as it arrives, charging each piece to the rate limiter
+// if there is one, so the download rate stays even rather than jumping by whole chunks.
+async fn read_body(
+    mut res: Response,
+    capacity: usize,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<Vec<u8>, RequestError> {
+    let mut body = Vec::with_capacity(capacity);
+    while let Some(piece) = res.chunk().await? {
+        if let Some(limiter) = rate_limiter {
+            limiter.take(piece.len() as u64).await;
+        }
+        body.extend_from_slice(&piece);
+    }
+    Ok(body)
+}
+
+// Waits for a request or its body, failing with ChunkTimeout once deadline passes, if there is one.
+async fn with_deadline<T>(
+    deadline: Option<TokioInstant>,
+    fut: impl Future<Output = Result<T, RequestError>>,
+) -> Result<T, TaskError> {
+    let result = match deadline {
+        Some(deadline) => timeout_at(deadline, fut)
+            .await
+            .map_err(|_| TaskError::ChunkTimeout)?,
+        None => fut.await,
+    };
+    result.map_err(TaskError::Request)
+}
+
+// HEAD, falling back to a ranged GET for servers that leave the length off HEAD responses. A
+// server that ignores the range sends the whole object with a 200, whose Content-Length is then
+// the size. Returns the last response along with the length, if either had one.
+async fn probe_content_length(
+    client: &Client,
+    url: &str,
+) -> Result<(Option<u64>, Response), RequestError> {
+    let res = client.head(url).send().await?;
+    if let Some(content_length) = header_content_length(&res) {
+        return Ok((Some(content_length), res));
+    }
+    let res = client.get(url).header(RANGE, "bytes=0-0").send().await?;
+    let content_length = if res.status() == StatusCode::OK {
+        header_content_length(&res)
+    } else {
+        content_range_total(&res)
+    };
+    Ok((content_length, res))
+}
+
+fn header_content_length(res: &Response) -> Option<u64> {
+    res.headers()
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+// the total from a "bytes <start>-<end>/<total>" Content-Range
+fn content_range_total(res: &Response) -> Option<u64> {
+    let range = res.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    range
+        .strip_prefix("bytes ")?
+        .split('/')
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+fn hash_hex(hash: &Hash) -> String {
+    git_index::hash_to_hex(hash)
+}
+
+fn url_for_hash(base: &str, hash: &Hash) -> String {
+    let hash_str = hash_hex(hash);
+    let url = format!("{}/objects/{}/{}", base, &hash_str[..2], &hash_str[2..]);
     url
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocking_tasks_stay_within_permits() {
+        let sem = Arc::new(Semaphore::new(2));
+        let running = Arc::new(AtomicU64::new(0));
+        let most_running = Arc::new(AtomicU64::new(0));
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let sem = sem.clone();
+                let running = running.clone();
+                let most_running = most_running.clone();
+                tokio::spawn(spawn_blocking_with_permit(sem, move || {
+                    let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now_running, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(50));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }))
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap().unwrap();
+        }
+        // never more than the permits, but both of them in use at once
+        assert_eq!(most_running.load(Ordering::SeqCst), 2);
+        assert_eq!(sem.available_permits(), 2);
+    }
+}
@@ -0,0 +1,39 @@
+use std::fs::File;
+use std::io::{Result as IoResult, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static LOG_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+
+// Like eprintln, and also appended to the --log-file if there is one.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::logging::log_line(&format!($($arg)*))
+    };
+}
+
+// Appends to path rather than truncating it, so the logs of resumed runs follow each other.
+pub fn open_log_file(path: &str) -> IoResult<()> {
+    let f = File::options().create(true).append(true).open(path)?;
+    // only opened once, at startup
+    let _ = LOG_FILE.set(Mutex::new(f));
+    Ok(())
+}
+
+pub fn log_line(line: &str) {
+    eprintln!("{}", line);
+    if let Some(f) = LOG_FILE.get() {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut f = f.lock().unwrap();
+        // losing the log shouldn't stop the download
+        let _ = writeln!(
+            f,
+            "[{}.{:03}] {}",
+            timestamp.as_secs(),
+            timestamp.subsec_millis(),
+            line
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use reqwest::Url;
+
+// Hosts that objects can be fetched from, with how often each has failed, so that a dead host
+// gets tried less rather than forever.
+pub struct Mirrors {
+    hosts: Vec<String>,
+    // the patch directory URL on each host, without a trailing slash
+    bases: Vec<String>,
+    failures: Vec<AtomicU64>,
+}
+
+impl Mirrors {
+    // bases are patch directory URLs, which all need a host
+    pub fn new(bases: Vec<Url>) -> Self {
+        let hosts = bases
+            .iter()
+            .map(|url| url.host_str().unwrap_or_default().to_string())
+            .collect();
+        let failures = bases.iter().map(|_| AtomicU64::new(0)).collect();
+        let bases = bases
+            .iter()
+            .map(|url| url.as_str().trim_end_matches('/').to_string())
+            .collect();
+        Mirrors {
+            hosts,
+            bases,
+            failures,
+        }
+    }
+
+    pub fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+
+    pub fn base(&self, i: usize) -> &str {
+        &self.bases[i]
+    }
+
+    // the host with the fewest failures, preferring earlier hosts on a tie
+    pub fn best(&self) -> usize {
+        self.best_where(|_| true).unwrap_or(0)
+    }
+
+    // like best, but never current unless it's the only host
+    pub fn best_other(&self, current: usize) -> usize {
+        self.best_where(|i| i != current).unwrap_or(current)
+    }
+
+    pub fn record_failure(&self, i: usize) {
+        self.failures[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn best_where<F: Fn(usize) -> bool>(&self, pred: F) -> Option<usize> {
+        (0..self.hosts.len())
+            .filter(|&i| pred(i))
+            .min_by_key(|&i| self.failures[i].load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// A token bucket holding up to a second's worth of bytes. Takers are charged up front and can
+// overdraw it, then wait until their part of the debt is paid off. Each one waits for the debt
+// including everything taken before it, so takers that overdraw at once are let through one
+// after another rather than all at the same moment. Bodies should be charged a piece at a time
+// as they arrive, so no single take is much bigger than the bucket.
+pub struct RateLimiter {
+    // bytes per second
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn take(&self, amount: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last).as_secs_f64() * self.rate;
+            bucket.tokens = (bucket.tokens + refill).min(self.rate);
+            bucket.last = now;
+            bucket.tokens -= amount as f64;
+            if bucket.tokens < 0. {
+                Duration::from_secs_f64(-bucket.tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[tokio::test(start_paused = true)]
+    async fn throughput_stays_at_rate() {
+        const RATE: u64 = 1_000_000;
+        const PIECE: u64 = 16 * 1024;
+        const PIECES_PER_TASK: u64 = 80;
+        const TASKS: u64 = 8;
+        let limiter = Arc::new(RateLimiter::new(RATE));
+        let start = Instant::now();
+        // when each piece got through, as seconds since the start
+        let times = Arc::new(Mutex::new(Vec::new()));
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|_| {
+                let limiter = limiter.clone();
+                let times = times.clone();
+                tokio::spawn(async move {
+                    for _ in 0..PIECES_PER_TASK {
+                        limiter.take(PIECE).await;
+                        let elapsed = start.elapsed().as_secs_f64();
+                        times.lock().unwrap().push(elapsed);
+                    }
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap();
+        }
+
+        // everything past the first second's worth in the bucket comes at the rate
+        let total = PIECE * PIECES_PER_TASK * TASKS;
+        let expected = (total - RATE) as f64 / RATE as f64;
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!((elapsed - expected).abs() < 0.1, "took {}s", elapsed);
+        // and evenly, not in bursts, over each second after the first
+        let times = times.lock().unwrap();
+        for second in 1..expected as u64 {
+            let window = second as f64..(second + 1) as f64;
+            let bytes = times.iter().filter(|t| window.contains(t)).count() as u64 * PIECE;
+            assert!(
+                bytes.abs_diff(RATE) <= 2 * PIECE,
+                "{} bytes in second {}",
+                bytes,
+                second
+            );
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::BTreeMap;
+use std::fs::{read, rename, File};
+use std::io::{Result as IoResult, Write};
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+// The state file records the index it was made for, and the hex hash of each file that was
+// completed under it. Returns those hashes by name, or nothing if the state is missing, can't be
+// read, or belongs to another index.
+pub fn load_state<P: AsRef<Path>>(path: P, index_name: &str) -> BTreeMap<String, String> {
+    (|| {
+        let state: Value = serde_json::from_slice(&read(path).ok()?).ok()?;
+        if state.get("index")?.as_str()? != index_name {
+            return None;
+        }
+        let files = state
+            .get("files")?
+            .as_object()?
+            .iter()
+            .filter_map(|(name, hash)| Some((name.clone(), hash.as_str()?.to_string())))
+            .collect();
+        Some(files)
+    })()
+    .unwrap_or_default()
+}
+
+// Written to a temporary file first, so an interrupted save leaves the previous state.
+pub fn save_state<'a, P: AsRef<Path>>(
+    path: P,
+    index_name: &str,
+    files: impl Iterator<Item = (&'a str, String)>,
+) -> IoResult<()> {
+    let files: serde_json::Map<String, Value> = files
+        .map(|(name, hash)| (name.to_string(), Value::String(hash)))
+        .collect();
+    let state = json!({
+        "index": index_name,
+        "files": files,
+    });
+    let path = path.as_ref();
+    let tmp_path = path.with_extension("json.tmp");
+    let mut f = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut f, &state)?;
+    f.flush()?;
+    drop(f);
+    rename(tmp_path, path)
+}
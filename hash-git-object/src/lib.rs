@@ -1,11 +1,49 @@
 use std::error::Error;
 use std::ffi::OsString;
-use std::fs::File;
-use std::io::{copy, Seek, SeekFrom};
+use std::fs::{read_dir, File};
+use std::io::{copy, stdin, Read, Seek, SeekFrom};
 use std::iter::Iterator;
+use std::path::Path;
 
 use la_tools::git_object;
 use la_tools::git_object::Digest;
+use la_tools::spool::spool;
+
+fn hash_file(path: &Path) -> Result<String, Box<dyn Error>> {
+    let mut f = File::open(path)?;
+    let file_size = f.seek(SeekFrom::End(0))?;
+    f.seek(SeekFrom::Start(0))?;
+    let mut digest = git_object::hash_sync(file_size);
+    copy(&mut f, &mut digest)?;
+    Ok(format!("{:x}", digest.finalize()))
+}
+
+// Hashes every regular file under dir, apart from any .git directory, named by its path relative
+// to the root with '/' separators like in an index.
+fn hash_dir(
+    dir: &Path,
+    prefix: &str,
+    out: &mut Vec<(String, String)>,
+) -> Result<(), Box<dyn Error>> {
+    for dir_entry in read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let file_name = dir_entry.file_name();
+        let file_name = file_name
+            .to_str()
+            .ok_or_else(|| format!("{} is not UTF-8", dir_entry.path().display()))?;
+        if file_name == ".git" {
+            continue;
+        }
+        let name = format!("{}{}", prefix, file_name);
+        let file_type = dir_entry.file_type()?;
+        if file_type.is_dir() {
+            hash_dir(&dir_entry.path(), &format!("{}/", name), out)?;
+        } else if file_type.is_file() {
+            out.push((name, hash_file(&dir_entry.path())?));
+        }
+    }
+    Ok(())
+}
 
 pub fn try_main<I, T>(itr: I) -> Result<i32, Box<dyn Error>>
 where
@@ -17,18 +55,37 @@ where
         .map(|i| i.into().to_string_lossy().into())
         .collect();
     if args.len() < 2 {
-        eprintln!("Usage: hash-git-object <file>");
+        eprintln!("Usage: hash-git-object <file or - for stdin>");
+        eprintln!("       hash-git-object --recursive <dir>");
         return Ok(1);
     }
 
+    if args[1] == "--recursive" {
+        if args.len() < 3 {
+            eprintln!("Usage: hash-git-object --recursive <dir>");
+            return Ok(1);
+        }
+        let mut hashes = Vec::new();
+        hash_dir(Path::new(&args[2]), "", &mut hashes)?;
+        // byte order, the same as entries in an index
+        hashes.sort();
+        for (name, hash) in hashes.iter() {
+            println!("{}  {}", hash, name);
+        }
+        return Ok(0);
+    }
+
     let file_name = &args[1];
-    let file_size = {
-        let mut f = File::open(file_name)?;
-        f.seek(SeekFrom::End(0))?
+    let (file_size, mut f): (u64, Box<dyn Read>) = if file_name == "-" {
+        spool(stdin())?
+    } else {
+        let file_size = {
+            let mut f = File::open(file_name)?;
+            f.seek(SeekFrom::End(0))?
+        };
+        (file_size, Box::new(File::open(file_name)?))
     };
 
-    let mut f = File::open(file_name)?;
-
     let mut digest = git_object::hash_sync(file_size);
     copy(&mut f, &mut digest)?;
     let value = digest.finalize();
@@ -1,16 +1,25 @@
 use std::error::Error;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
 
 const APPLET_NAMES: &[&str] = &[
+    CREATE_GIT_INDEX,
+    DIFF_GIT_INDEX,
     EXTRACT_GIT_OBJECT,
     HASH_GIT_OBJECT,
+    LIST_GIT_INDEX,
     MAKE_GIT_OBJECT,
     PATCH_GIT_INDEX,
+    VERIFY_GIT_OBJECT,
 ];
+const CREATE_GIT_INDEX: &str = "create-git-index";
+const DIFF_GIT_INDEX: &str = "diff-git-index";
 const EXTRACT_GIT_OBJECT: &str = "extract-git-object";
 const HASH_GIT_OBJECT: &str = "hash-git-object";
+const LIST_GIT_INDEX: &str = "list-git-index";
 const MAKE_GIT_OBJECT: &str = "make-git-object";
 const PATCH_GIT_INDEX: &str = "patch-git-index";
+const VERIFY_GIT_OBJECT: &str = "verify-git-object";
 
 pub fn try_main<I, T>(itr: I) -> Result<i32, Box<dyn Error>>
 where
@@ -19,33 +28,49 @@ where
 {
     let args: Vec<OsString> = itr.into_iter().map(|i| i.into()).collect();
 
-    for skip in 0..=1 {
-        if args.len() < skip + 1 {
-            eprintln!("Missing applet name");
-            usage();
-            return Ok(127);
+    // busybox style, run through a link or copy named after the applet
+    if let Some(applet_name) = args.first().and_then(|arg0| invoked_name(arg0)) {
+        if let Some(r) = try_dispatch(&applet_name, &args) {
+            return r;
         }
+    }
 
-        let applet_name = args[skip].to_string_lossy();
-
-        if let Some(r) = try_dispatch(&applet_name, &args[skip..]) {
-            return r;
-        } else if skip == 1 {
-            eprintln!("Invalid applet name: {}", &applet_name);
+    // otherwise the applet is named by the first argument
+    let applet_name = match args.get(1) {
+        Some(applet_name) => applet_name.to_string_lossy(),
+        None => {
+            eprintln!("Missing applet name");
             usage();
             return Ok(127);
         }
+    };
+
+    if let Some(r) = try_dispatch(&applet_name, &args[1..]) {
+        return r;
     }
 
-    Ok(0)
+    eprintln!("Invalid applet name: {}", &applet_name);
+    usage();
+    Ok(127)
+}
+
+// the file name argv[0] was run as, without its directory or the .exe it has on Windows
+fn invoked_name(arg0: &OsStr) -> Option<String> {
+    let file_name = Path::new(arg0).file_name()?.to_string_lossy();
+    let applet_name = file_name.strip_suffix(".exe").unwrap_or(&file_name);
+    Some(applet_name.to_string())
 }
 
 fn try_dispatch(applet_name: &str, args: &[OsString]) -> Option<Result<i32, Box<dyn Error>>> {
     match applet_name {
+        CREATE_GIT_INDEX => Some(create_git_index::try_main(args)),
+        DIFF_GIT_INDEX => Some(diff_git_index::try_main(args)),
         EXTRACT_GIT_OBJECT => Some(extract_git_object::try_main(args)),
         HASH_GIT_OBJECT => Some(hash_git_object::try_main(args)),
+        LIST_GIT_INDEX => Some(list_git_index::try_main(args)),
         MAKE_GIT_OBJECT => Some(make_git_object::try_main(args)),
         PATCH_GIT_INDEX => Some(patch_git_index::try_main(args)),
+        VERIFY_GIT_OBJECT => Some(verify_git_object::try_main(args)),
         _ => None,
     }
 }
@@ -57,3 +82,22 @@ fn usage() {
         eprintln!("    {}", applet_name);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invoked_name_of_arg0() {
+        let name = |arg0: &str| invoked_name(OsStr::new(arg0)).unwrap();
+        assert_eq!(name("hash-git-object"), HASH_GIT_OBJECT);
+        assert_eq!(name("/usr/bin/hash-git-object"), HASH_GIT_OBJECT);
+        assert_eq!(name("./hash-git-object.exe"), HASH_GIT_OBJECT);
+        // only the one suffix, and only at the end
+        assert_eq!(name("hash-git-object.exe.exe"), "hash-git-object.exe");
+        assert_eq!(name("hash.exe-git-object"), "hash.exe-git-object");
+        assert_eq!(name("la-tools-multi"), "la-tools-multi");
+        assert_eq!(invoked_name(OsStr::new("/")), None);
+        assert_eq!(invoked_name(OsStr::new("")), None);
+    }
+}
@@ -0,0 +1,23 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// git hash-object of a file containing "hi\n"
+const HASH: &str = "45b983be36b73c0788dc9cbcb76cbb80fc7bb057";
+
+#[cfg(unix)]
+#[test]
+fn dispatch_by_link_name() {
+    let dir = tempfile::tempdir().unwrap();
+    let link = dir.path().join("hash-git-object");
+    std::os::unix::fs::symlink(env!("CARGO_BIN_EXE_la-tools-multi"), &link).unwrap();
+    let mut child = Command::new(&link)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"hi\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().starts_with(HASH));
+}
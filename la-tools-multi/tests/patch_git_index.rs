@@ -0,0 +1,75 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use la_tools::git_index;
+use la_tools::git_index::{EntryHeader, FileHeader, OwnedView};
+
+const HASH: &str = "45b983be36b73c0788dc9cbcb76cbb80fc7bb057";
+
+// an index with a single empty file in it
+fn build_index() -> Vec<u8> {
+    let mut header = FileHeader {
+        magic: *b"DIRC",
+        ..Default::default()
+    };
+    header.version.set(2);
+    let mut index: OwnedView = OwnedView {
+        header,
+        entries: Vec::new(),
+        footer: vec![0; 20],
+    };
+    let mut entry = EntryHeader::default();
+    entry.mode.set(0o100644);
+    index.insert_entry(entry, b"a/b.txt");
+    let mut bin = Vec::new();
+    git_index::write(&index.view(), &mut bin);
+    bin
+}
+
+// runs patch-git-index on index, returning the patched index
+fn patch(args: &[&str], index: &[u8]) -> Vec<u8> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_la-tools-multi"))
+        .arg("patch-git-index")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(index).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    output.stdout
+}
+
+#[test]
+fn patch_git_index_applet() {
+    let output = patch(&["a/b.txt", "3", HASH], &build_index());
+    let index = git_index::parse(&output).unwrap();
+    let entry = index.find(b"a/b.txt").unwrap();
+    assert_eq!(entry.header.size.get(), 3);
+    assert_eq!(git_index::hash_to_hex(&entry.header.sha1), HASH);
+}
+
+#[test]
+fn patch_git_index_patches_every_stage() {
+    // a merge conflict, with the base, ours and theirs versions at stages 1 to 3
+    let mut index = OwnedView::from(&git_index::parse(&build_index()).unwrap());
+    let entry = index.entries.pop().unwrap();
+    for stage in 1..=3 {
+        let mut entry = entry.clone();
+        let flags = &mut entry.header.flags;
+        flags.set(flags.get() | (stage << git_index::STAGE_SHIFT));
+        index.entries.push(entry);
+    }
+    let mut bin = Vec::new();
+    git_index::write(&index.view(), &mut bin);
+
+    let output = patch(&["a/b.txt", "3", HASH], &bin);
+    let index = git_index::parse(&output).unwrap();
+    assert_eq!(index.entries.len(), 3);
+    for entry in index.entries.iter() {
+        assert_eq!(entry.header.size.get(), 3);
+        assert_eq!(git_index::hash_to_hex(&entry.header.sha1), HASH);
+    }
+}
@@ -1,13 +1,23 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult};
 use std::mem;
+use std::path::Path;
 
 use byteorder::NetworkEndian;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use static_assertions::assert_eq_size;
 use zerocopy::byteorder::{I32, U16, U32};
 use zerocopy::{AsBytes, FromBytes, LayoutVerified, Unaligned};
 
 // https://git-scm.com/docs/index-format
 
-#[derive(AsBytes, Debug, FromBytes, Unaligned)]
+#[derive(AsBytes, Clone, Debug, Default, FromBytes, Unaligned)]
 #[repr(C)]
 pub struct FileHeader {
     pub magic: [u8; 4],
@@ -17,7 +27,7 @@ pub struct FileHeader {
 
 assert_eq_size!(FileHeader, [u8; 12]);
 
-#[derive(AsBytes, Debug, FromBytes, Unaligned)]
+#[derive(AsBytes, Clone, Debug, Default, FromBytes, Unaligned)]
 #[repr(C)]
 pub struct EntryHeader {
     pub ctime_s: I32<NetworkEndian>,
@@ -36,39 +46,642 @@ pub struct EntryHeader {
 
 assert_eq_size!(EntryHeader, [u8; 62]);
 
+// same layout as EntryHeader, for indices of repositories using SHA-256 object ids
+#[derive(AsBytes, Clone, Debug, Default, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct EntryHeaderSha256 {
+    pub ctime_s: I32<NetworkEndian>,
+    pub ctime_ns: I32<NetworkEndian>,
+    pub mtime_s: I32<NetworkEndian>,
+    pub mtime_ns: I32<NetworkEndian>,
+    pub dev: U32<NetworkEndian>,
+    pub ino: U32<NetworkEndian>,
+    pub mode: U32<NetworkEndian>,
+    pub uid: U32<NetworkEndian>,
+    pub gid: U32<NetworkEndian>,
+    pub size: U32<NetworkEndian>,
+    pub sha256: Sha256Hash,
+    pub flags: U16<NetworkEndian>,
+}
+
+assert_eq_size!(EntryHeaderSha256, [u8; 74]);
+
+#[derive(AsBytes, Clone, Debug, Default, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct ExtensionHeader {
+    pub signature: [u8; 4],
+    pub size: U32<NetworkEndian>,
+}
+
+assert_eq_size!(ExtensionHeader, [u8; 8]);
+
 pub type Hash = [u8; 20];
+pub type Sha256Hash = [u8; 32];
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum HexError {
+    BadDigit,
+    // not two digits per byte of a hash
+    BadLength,
+}
+
+// How the applets that print entries print them, from their --format option.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Text,
+}
+
+// Hash algorithm of the repository an index belongs to. It decides the size of the object id in
+// each entry as well as the algorithm and size of the trailing checksum. Nothing in the index
+// itself records it, so it has to be known up front.
+pub trait ObjectFormat {
+    type EntryHeader: AsBytes + Clone + Debug + Default + EntryFields + FromBytes + Unaligned;
+    type Digest: Digest;
+}
+
+// fields every entry header layout has, for code that is generic over the object format
+pub trait EntryFields {
+    fn flags(&self) -> &U16<NetworkEndian>;
+    fn flags_mut(&mut self) -> &mut U16<NetworkEndian>;
+    fn hash(&self) -> &[u8];
+    fn hash_mut(&mut self) -> &mut [u8];
+    fn mode(&self) -> u32;
+    fn size(&self) -> u32;
+
+    // zeroes the times, device, inode and owner, which only mean something on the machine that
+    // wrote them, leaving the mode, size, hash and flags alone
+    fn normalize(&mut self);
+
+    fn assume_valid(&self) -> bool {
+        self.flags().get() & FLAG_ASSUME_VALID != 0
+    }
+
+    fn extended(&self) -> bool {
+        self.flags().get() & FLAG_EXTENDED != 0
+    }
+
+    // 0 normally, 1 to 3 for the base, ours and theirs sides of a merge conflict
+    fn stage(&self) -> u8 {
+        ((self.flags().get() & STAGE_MASK) >> STAGE_SHIFT) as u8
+    }
+
+    // saturates at NAME_LENGTH_MASK, in which case the name has to be scanned for its NUL
+    fn name_length(&self) -> u16 {
+        self.flags().get() & NAME_LENGTH_MASK
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha1Format;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha256Format;
+
+impl ObjectFormat for Sha1Format {
+    type EntryHeader = EntryHeader;
+    type Digest = Sha1;
+}
+
+impl ObjectFormat for Sha256Format {
+    type EntryHeader = EntryHeaderSha256;
+    type Digest = Sha256;
+}
+
+// the flags word accessors of EntryFields, so callers with a concrete header don't need the trait
+impl EntryHeader {
+    pub fn assume_valid(&self) -> bool {
+        EntryFields::assume_valid(self)
+    }
+
+    pub fn extended(&self) -> bool {
+        EntryFields::extended(self)
+    }
+
+    pub fn stage(&self) -> u8 {
+        EntryFields::stage(self)
+    }
+
+    pub fn name_length(&self) -> u16 {
+        EntryFields::name_length(self)
+    }
+}
+
+impl EntryFields for EntryHeader {
+    fn flags(&self) -> &U16<NetworkEndian> {
+        &self.flags
+    }
+
+    fn flags_mut(&mut self) -> &mut U16<NetworkEndian> {
+        &mut self.flags
+    }
+
+    fn hash(&self) -> &[u8] {
+        &self.sha1
+    }
+
+    fn hash_mut(&mut self) -> &mut [u8] {
+        &mut self.sha1
+    }
+
+    fn mode(&self) -> u32 {
+        self.mode.get()
+    }
+
+    fn size(&self) -> u32 {
+        self.size.get()
+    }
+
+    fn normalize(&mut self) {
+        self.ctime_s.set(0);
+        self.ctime_ns.set(0);
+        self.mtime_s.set(0);
+        self.mtime_ns.set(0);
+        self.dev.set(0);
+        self.ino.set(0);
+        self.uid.set(0);
+        self.gid.set(0);
+    }
+}
+
+impl EntryFields for EntryHeaderSha256 {
+    fn flags(&self) -> &U16<NetworkEndian> {
+        &self.flags
+    }
+
+    fn flags_mut(&mut self) -> &mut U16<NetworkEndian> {
+        &mut self.flags
+    }
+
+    fn hash(&self) -> &[u8] {
+        &self.sha256
+    }
+
+    fn hash_mut(&mut self) -> &mut [u8] {
+        &mut self.sha256
+    }
+
+    fn mode(&self) -> u32 {
+        self.mode.get()
+    }
+
+    fn size(&self) -> u32 {
+        self.size.get()
+    }
+
+    fn normalize(&mut self) {
+        self.ctime_s.set(0);
+        self.ctime_ns.set(0);
+        self.mtime_s.set(0);
+        self.mtime_ns.set(0);
+        self.dev.set(0);
+        self.ino.set(0);
+        self.uid.set(0);
+        self.gid.set(0);
+    }
+}
+
+pub const FLAG_ASSUME_VALID: u16 = 0x8000;
+pub const FLAG_EXTENDED: u16 = 0x4000;
+pub const STAGE_MASK: u16 = 0x3000;
+pub const STAGE_SHIFT: u32 = 12;
+pub const NAME_LENGTH_MASK: u16 = 0x0fff;
 
 #[derive(Debug)]
-pub struct ViewEntry<'a> {
-    pub header: &'a EntryHeader,
-    pub name: &'a [u8],
+pub struct ViewEntry<'a, F: ObjectFormat = Sha1Format> {
+    pub header: &'a F::EntryHeader,
+    pub extended_flags: Option<u16>,
+    // version 4 names are prefix compressed on disk, so they can't always be borrowed
+    pub name: Cow<'a, [u8]>,
 }
 
 #[derive(Debug)]
-pub struct ViewEntryMut<'a> {
-    pub header: &'a mut EntryHeader,
-    pub name: &'a mut [u8],
+pub struct ViewEntryMut<'a, F: ObjectFormat = Sha1Format> {
+    pub header: &'a mut F::EntryHeader,
+    pub extended_flags: Option<&'a mut U16<NetworkEndian>>,
+    pub name: Cow<'a, [u8]>,
+    // name as stored, including padding or prefix compression, for recomputing the checksum
+    raw_name: &'a [u8],
 }
 
 #[derive(Debug)]
-pub struct View<'a> {
+pub struct View<'a, F: ObjectFormat = Sha1Format> {
     pub header: &'a FileHeader,
-    pub entries: Vec<ViewEntry<'a>>,
+    pub entries: Vec<ViewEntry<'a, F>>,
     pub footer: &'a [u8],
 }
 
 #[derive(Debug)]
-pub struct ViewMut<'a> {
+pub struct ViewMut<'a, F: ObjectFormat = Sha1Format> {
     pub header: &'a mut FileHeader,
-    pub entries: Vec<ViewEntryMut<'a>>,
+    pub entries: Vec<ViewEntryMut<'a, F>>,
     pub footer: &'a mut [u8],
 }
 
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ParseError {
+    BadChecksum,
+    // the bytes after the declared entries don't split into extensions, so the entry count is
+    // probably too low
+    BadExtensions,
+    BadMagic,
+    // name padding isn't NULs, or a version 4 name can't be decompressed
+    BadName,
+    // the name length in the entry flags doesn't match the NUL terminated name
+    NameLengthMismatch,
+    // Ran out of bytes after parsed of the declared entries. The checksum is checked first, so
+    // for a bad checksum the file was cut short, and otherwise the declared count is too high.
+    // Both are 0 if the file is too short for its header and checksum.
+    Truncated { parsed: u32, declared: u32 },
+    UnsupportedVersion(u32),
+}
+
+// Ran out of bytes somewhere in an entry. The entry loop fills in the counts with with_counts.
+const OUT_OF_BYTES: ParseError = ParseError::Truncated {
+    parsed: 0,
+    declared: 0,
+};
+
+fn with_counts(e: ParseError, parsed: u32, declared: u32) -> ParseError {
+    match e {
+        ParseError::Truncated { .. } => ParseError::Truncated { parsed, declared },
+        e => e,
+    }
+}
+
+// Cached tree object of a directory, from the TREE extension. Invalidated directories have no
+// entry count or hash, but still list their subtrees.
+#[derive(Clone, Debug)]
+pub struct TreeCache {
+    // path component relative to the parent, empty for the root
+    pub path: Vec<u8>,
+    pub entry_count: Option<u32>,
+    pub hash: Option<Hash>,
+    pub subtrees: Vec<TreeCache>,
+}
+
+// Owned counterpart of View, for edits that change the byte layout such as adding or removing
+// entries. Serialize it with write(&owned.view(), ...).
+#[derive(Clone, Debug)]
+pub struct OwnedView<F: ObjectFormat = Sha1Format> {
+    pub header: FileHeader,
+    pub entries: Vec<OwnedEntry<F>>,
+    pub footer: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct OwnedEntry<F: ObjectFormat = Sha1Format> {
+    pub header: F::EntryHeader,
+    pub extended_flags: Option<u16>,
+    pub name: Vec<u8>,
+}
+
+// as "<octal mode> <hex hash> <size> <name>", with the name decoded lossily
+fn fmt_entry(header: &impl EntryFields, name: &[u8], f: &mut Formatter) -> FmtResult {
+    write!(
+        f,
+        "{:06o} {} {} {}",
+        header.mode(),
+        bytes_to_hex(header.hash()),
+        header.size(),
+        String::from_utf8_lossy(name)
+    )
+}
+
+// the same fields as fmt_entry, as a JSON object
+pub fn entry_json<F: ObjectFormat>(e: &ViewEntry<F>) -> Value {
+    json!({
+        "hash": bytes_to_hex(e.header.hash()),
+        "size": e.header.size(),
+        "mode": format!("{:o}", e.header.mode()),
+        "name": String::from_utf8_lossy(&e.name),
+    })
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(OutputFormat::Json),
+            "text" => Some(OutputFormat::Text),
+            _ => None,
+        }
+    }
+}
+
+impl<F: ObjectFormat> Display for ViewEntry<'_, F> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        fmt_entry(self.header, &self.name, f)
+    }
+}
+
+impl<F: ObjectFormat> Display for OwnedEntry<F> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        fmt_entry(&self.header, &self.name, f)
+    }
+}
+
+impl Display for HexError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for HexError {}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for ParseError {}
+
+// the footer is any extension data followed by a checksum over everything before it
+fn split_footer<F: ObjectFormat>(footer: &[u8]) -> (&[u8], &[u8]) {
+    footer.split_at(footer.len().saturating_sub(hash_size::<F>()))
+}
+
+fn hash_size<F: ObjectFormat>() -> usize {
+    <F::Digest as Digest>::output_size()
+}
+
+// Entries are sorted the way git sorts them, byte-wise by name and then by stage, so "foo" sorts
+// before "foo.c" which sorts before "foo/bar". Lookups find the lowest stage entry for a name,
+// which for an index without merge conflicts is the only one.
+fn find_position<T>(entries: &[T], name: &[u8], entry_name: impl Fn(&T) -> &[u8]) -> Option<usize> {
+    let i = entries.partition_point(|e| entry_name(e) < name);
+    if entries.get(i).map(&entry_name) == Some(name) {
+        Some(i)
+    } else {
+        None
+    }
+}
+
+// Whether entries are in the order find_position expects, with no name and stage pair twice.
+// parse doesn't check this, so a hand-built or corrupt index can break lookups.
+fn is_sorted_by_key<T>(entries: &[T], key: impl Fn(&T) -> (&[u8], u8)) -> bool {
+    entries.windows(2).all(|w| key(&w[0]) < key(&w[1]))
+}
+
+impl<'a, F: ObjectFormat> ViewEntry<'a, F> {
+    // Interprets the name as a relative path, or None if it isn't UTF-8. The stored separator is
+    // always '/' even on Windows, so join it onto a base directory with Path::join rather than
+    // by string concatenation.
+    pub fn path(&self) -> Option<&Path> {
+        Some(Path::new(std::str::from_utf8(&self.name).ok()?))
+    }
+}
+
+impl<'a, F: ObjectFormat> View<'a, F> {
+    pub fn paths(&self) -> impl Iterator<Item = Option<&Path>> {
+        self.entries.iter().map(|e| e.path())
+    }
+
+    pub fn find(&self, name: &[u8]) -> Option<&ViewEntry<'a, F>> {
+        let i = find_position(&self.entries, name, |e| &e.name)?;
+        Some(&self.entries[i])
+    }
+
+    pub fn is_sorted(&self) -> bool {
+        is_sorted_by_key(&self.entries, |e| (&e.name, e.header.stage()))
+    }
+
+    pub fn extensions(&self) -> &'a [u8] {
+        split_footer::<F>(self.footer).0
+    }
+
+    pub fn checksum(&self) -> &'a [u8] {
+        split_footer::<F>(self.footer).1
+    }
+}
+
+impl<'a, F: ObjectFormat> ViewMut<'a, F> {
+    pub fn find_mut(&mut self, name: &[u8]) -> Option<&mut ViewEntryMut<'a, F>> {
+        let i = find_position(&self.entries, name, |e| &e.name)?;
+        Some(&mut self.entries[i])
+    }
+
+    // Every entry of the given name, one per stage for a merge conflict, lowest stage first.
+    // Empty if there are none.
+    pub fn find_all_mut(&mut self, name: &[u8]) -> &mut [ViewEntryMut<'a, F>] {
+        let start = self.entries.partition_point(|e| &e.name[..] < name);
+        let len = self.entries[start..].partition_point(|e| &e.name[..] == name);
+        &mut self.entries[start..start + len]
+    }
+
+    pub fn extensions(&self) -> &[u8] {
+        split_footer::<F>(self.footer).0
+    }
+
+    pub fn checksum(&self) -> &[u8] {
+        split_footer::<F>(self.footer).1
+    }
+
+    // Zeroes the volatile fields of every entry, see EntryFields::normalize. The checksum has to be
+    // recomputed with finalize_checksum afterwards.
+    pub fn normalize(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.header.normalize();
+        }
+    }
+
+    // Recomputes the trailing checksum after the entries have been edited in place.
+    pub fn finalize_checksum(&mut self) {
+        let mut state = F::Digest::new();
+        state.update(self.header.as_bytes());
+        for entry in self.entries.iter() {
+            state.update(entry.header.as_bytes());
+            if let Some(extended_flags) = &entry.extended_flags {
+                state.update(extended_flags.as_bytes());
+            }
+            state.update(entry.raw_name);
+        }
+        let extensions_len = self.footer.len() - hash_size::<F>();
+        let (extensions, checksum) = self.footer.split_at_mut(extensions_len);
+        state.update(extensions);
+        checksum.copy_from_slice(&state.finalize());
+    }
+}
+
+impl<F: ObjectFormat> OwnedView<F> {
+    pub fn view(&self) -> View<'_, F> {
+        View {
+            header: &self.header,
+            entries: self
+                .entries
+                .iter()
+                .map(|e| ViewEntry {
+                    header: &e.header,
+                    extended_flags: e.extended_flags,
+                    name: Cow::Borrowed(&e.name),
+                })
+                .collect(),
+            footer: &self.footer,
+        }
+    }
+
+    pub fn find(&self, name: &[u8]) -> Option<&OwnedEntry<F>> {
+        let i = find_position(&self.entries, name, |e| &e.name)?;
+        Some(&self.entries[i])
+    }
+
+    pub fn is_sorted(&self) -> bool {
+        is_sorted_by_key(&self.entries, |e| (&e.name, e.header.stage()))
+    }
+
+    // Inserts an entry keeping the entries sorted, replacing and returning any existing entry of
+    // the same name. The name length bits of the header flags are filled in from name, and the
+    // TREE extension is invalidated down to the entry's directory, see invalidate_tree.
+    pub fn insert_entry(
+        &mut self,
+        mut header: F::EntryHeader,
+        name: &[u8],
+    ) -> Option<OwnedEntry<F>> {
+        let name_length = name.len().min(NAME_LENGTH_MASK as usize) as u16;
+        let flags = header.flags().get();
+        header
+            .flags_mut()
+            .set((flags & !NAME_LENGTH_MASK) | name_length);
+        let entry = OwnedEntry {
+            header,
+            extended_flags: None,
+            name: name.to_vec(),
+        };
+        let replaced = match find_position(&self.entries, name, |e| &e.name) {
+            Some(i) => Some(mem::replace(&mut self.entries[i], entry)),
+            None => {
+                let i = self.entries.partition_point(|e| &e.name[..] < name);
+                self.entries.insert(i, entry);
+                None
+            }
+        };
+        self.header.entry_count.set(self.entries.len() as u32);
+        invalidate_tree::<F>(&mut self.footer, name);
+        replaced
+    }
+
+    // Like insert_entry, the TREE extension is invalidated down to the removed entry's directory.
+    pub fn remove_entry(&mut self, name: &[u8]) -> Option<OwnedEntry<F>> {
+        let i = find_position(&self.entries, name, |e| &e.name)?;
+        let removed = self.entries.remove(i);
+        self.header.entry_count.set(self.entries.len() as u32);
+        invalidate_tree::<F>(&mut self.footer, name);
+        Some(removed)
+    }
+}
+
+// Marks the cached trees of the root and each directory leading to the entry name as invalid in
+// the footer's TREE extension, the way git does when an entry changes, so they aren't written
+// back with their stale hashes. Other directories keep theirs. A TREE extension that doesn't
+// parse is dropped, and the checksum space at the end of the footer is kept.
+fn invalidate_tree<F: ObjectFormat>(footer: &mut Vec<u8>, name: &[u8]) {
+    let (extensions, checksum) = split_footer::<F>(footer);
+    let mut out = Vec::with_capacity(footer.len());
+    let mut reader = SliceReader(extensions);
+    while reader.len() > 0 {
+        let (signature, data) = match take_extension(&mut reader) {
+            Some(extension) => extension,
+            // not an extension list, so leave it alone for write to copy through
+            None => return,
+        };
+        let data = if &signature == b"TREE" {
+            let mut tree_reader = SliceReader(data);
+            let mut tree = Vec::new();
+            let root = Some((&b""[..], name));
+            match invalidate_tree_node(&mut tree_reader, hash_size::<F>(), root, &mut tree) {
+                Some(()) if tree_reader.len() == 0 => tree,
+                _ => continue,
+            }
+        } else {
+            data.to_vec()
+        };
+        out.extend_from_slice(&signature);
+        out.extend_from_slice(U32::<NetworkEndian>::new(data.len() as u32).as_bytes());
+        out.extend_from_slice(&data);
+    }
+    out.extend_from_slice(checksum);
+    *footer = out;
+}
+
+// Copies one tree node and its subtrees from reader to out. target is the path component this
+// node has to have to be on the way to the changed entry, with the rest of the entry's path
+// below it. Nodes on the way are written with an entry count of -1 and no hash.
+fn invalidate_tree_node(
+    reader: &mut SliceReader,
+    hash_len: usize,
+    target: Option<(&[u8], &[u8])>,
+    out: &mut Vec<u8>,
+) -> Option<()> {
+    let start = reader.0;
+    let path = take_until(reader, b'\0')?;
+    let entry_count = take_decimal::<i64>(reader, b' ')?;
+    let subtree_count = take_decimal::<usize>(reader, b'\n')?;
+    if entry_count >= 0 {
+        reader.take_front(hash_len)?;
+    }
+    let rest = match target {
+        Some((component, rest)) if component == path => Some(rest),
+        _ => None,
+    };
+    match rest {
+        Some(_) => {
+            out.extend_from_slice(path);
+            out.extend_from_slice(format!("\0-1 {}\n", subtree_count).as_bytes());
+        }
+        None => out.extend_from_slice(&start[..start.len() - reader.len()]),
+    }
+    // the subdirectory the entry is in, if it isn't directly in this one
+    let subtarget = rest.and_then(|rest| {
+        let slash_pos = rest.iter().position(|&x| x == b'/')?;
+        Some((&rest[..slash_pos], &rest[slash_pos + 1..]))
+    });
+    for _ in 0..subtree_count {
+        invalidate_tree_node(reader, hash_len, subtarget, out)?;
+    }
+    Some(())
+}
+
+impl<F: ObjectFormat> From<&View<'_, F>> for OwnedView<F> {
+    fn from(view: &View<F>) -> Self {
+        OwnedView {
+            header: view.header.clone(),
+            entries: view
+                .entries
+                .iter()
+                .map(|e| OwnedEntry {
+                    header: e.header.clone(),
+                    extended_flags: e.extended_flags,
+                    name: e.name.to_vec(),
+                })
+                .collect(),
+            footer: view.footer.to_vec(),
+        }
+    }
+}
+
+impl<F: ObjectFormat> From<&ViewMut<'_, F>> for OwnedView<F> {
+    fn from(view: &ViewMut<F>) -> Self {
+        OwnedView {
+            header: view.header.clone(),
+            entries: view
+                .entries
+                .iter()
+                .map(|e| OwnedEntry {
+                    header: e.header.clone(),
+                    extended_flags: e.extended_flags.as_ref().map(|f| f.get()),
+                    name: e.name.to_vec(),
+                })
+                .collect(),
+            footer: view.footer.to_vec(),
+        }
+    }
+}
+
 // based on BufferView from fuchsia packet crate
 struct SliceReader<'a>(&'a [u8]);
 
 impl<'a> SliceReader<'a> {
-    fn iter(&self) -> std::slice::Iter<u8> {
+    fn iter(&self) -> std::slice::Iter<'_, u8> {
         self.0.iter()
     }
 
@@ -78,7 +691,7 @@ impl<'a> SliceReader<'a> {
 
     fn take_front(&mut self, n: usize) -> Option<&'a [u8]> {
         if self.0.len() >= n {
-            let (prefix, rest) = mem::replace(&mut self.0, &[]).split_at(n);
+            let (prefix, rest) = mem::take(&mut self.0).split_at(n);
             self.0 = rest;
             Some(prefix)
         } else {
@@ -95,7 +708,7 @@ impl<'a> SliceReader<'a> {
 struct SliceReaderMut<'a>(&'a mut [u8]);
 
 impl<'a> SliceReaderMut<'a> {
-    fn iter(&self) -> std::slice::Iter<u8> {
+    fn iter(&self) -> std::slice::Iter<'_, u8> {
         self.0.iter()
     }
 
@@ -105,7 +718,7 @@ impl<'a> SliceReaderMut<'a> {
 
     fn take_front(&mut self, n: usize) -> Option<&'a mut [u8]> {
         if self.0.len() >= n {
-            let (prefix, rest) = mem::replace(&mut self.0, &mut []).split_at_mut(n);
+            let (prefix, rest) = mem::take(&mut self.0).split_at_mut(n);
             self.0 = rest;
             Some(prefix)
         } else {
@@ -119,76 +732,914 @@ impl<'a> SliceReaderMut<'a> {
     }
 }
 
+// checks the file as a whole, before it gets split up into entries
+fn check_file<F: ObjectFormat>(bin: &[u8]) -> Result<(), ParseError> {
+    let mut reader = SliceReader(bin);
+    let header = reader.take_obj_front::<FileHeader>().ok_or(OUT_OF_BYTES)?;
+    if &header.magic != b"DIRC" {
+        return Err(ParseError::BadMagic);
+    }
+    let version = header.version.get();
+    if !(2..=4).contains(&version) {
+        return Err(ParseError::UnsupportedVersion(version));
+    }
+    if reader.len() < hash_size::<F>() {
+        return Err(OUT_OF_BYTES);
+    }
+    let (data, checksum) = bin.split_at(bin.len() - hash_size::<F>());
+    if F::Digest::digest(data)[..] != *checksum {
+        return Err(ParseError::BadChecksum);
+    }
+    Ok(())
+}
+
 fn round_up(x: usize, increment: usize) -> usize {
-    (x + increment - 1) / increment * increment
+    x.div_ceil(increment) * increment
+}
+
+// extended flags only exist from version 3 onwards, and only when the entry asks for them
+fn has_extended_flags(version: u32, header: &impl EntryFields) -> bool {
+    version >= 3 && header.extended()
 }
 
-fn take_name<'a>(reader: &mut SliceReader<'a>) -> Option<&'a [u8]> {
-    let nul_pos = reader.iter().position(|&x| x == b'\0')?;
+fn take_name<'a>(
+    reader: &mut SliceReader<'a>,
+    header_size: usize,
+) -> Result<&'a [u8], ParseError> {
+    let nul_pos = reader
+        .iter()
+        .position(|&x| x == b'\0')
+        .ok_or(OUT_OF_BYTES)?;
     // size of entire entry including name is NUL padded to be multiple of 8
-    let header_size = mem::size_of::<EntryHeader>();
     let size = round_up(nul_pos + header_size + 1, 8) - header_size;
-    let (text_bytes, nul_bytes) = reader.take_front(size)?.split_at(nul_pos);
+    let (text_bytes, nul_bytes) = reader
+        .take_front(size)
+        .ok_or(OUT_OF_BYTES)?
+        .split_at(nul_pos);
     if !nul_bytes.iter().all(|&x| x == b'\0') {
-        return None;
+        return Err(ParseError::BadName);
     }
-    Some(text_bytes)
+    Ok(text_bytes)
 }
 
-fn take_name_mut<'a>(reader: &mut SliceReaderMut<'a>) -> Option<&'a mut [u8]> {
-    let nul_pos = reader.iter().position(|&x| x == b'\0')?;
+fn take_name_mut<'a>(
+    reader: &mut SliceReaderMut<'a>,
+    header_size: usize,
+) -> Result<(&'a [u8], &'a [u8]), ParseError> {
+    let nul_pos = reader
+        .iter()
+        .position(|&x| x == b'\0')
+        .ok_or(OUT_OF_BYTES)?;
     // size of entire entry including name is NUL padded to be multiple of 8
-    let header_size = mem::size_of::<EntryHeader>();
     let size = round_up(nul_pos + header_size + 1, 8) - header_size;
-    let (text_bytes, nul_bytes) = reader.take_front(size)?.split_at_mut(nul_pos);
+    let raw_bytes: &'a [u8] = reader.take_front(size).ok_or(OUT_OF_BYTES)?;
+    let (text_bytes, nul_bytes) = raw_bytes.split_at(nul_pos);
     if !nul_bytes.iter().all(|&x| x == b'\0') {
-        return None;
+        return Err(ParseError::BadName);
     }
-    Some(text_bytes)
+    Ok((text_bytes, raw_bytes))
 }
 
-pub fn parse<'a>(bin: &'a [u8]) -> Option<View<'a>> {
-    let mut reader = SliceReader(&bin);
-    let header = reader.take_obj_front::<FileHeader>()?;
-    if header.version.get() != 2 {
-        return None;
+// Names shorter than NAME_LENGTH_MASK have their exact length in the flags. Longer ones store the
+// mask instead, and only the NUL scan says how long they are.
+fn check_name_length(header: &impl EntryFields, name: &[u8]) -> Result<(), ParseError> {
+    let declared = header.name_length() as usize;
+    if declared == name.len().min(NAME_LENGTH_MASK as usize) {
+        Ok(())
+    } else {
+        Err(ParseError::NameLengthMismatch)
     }
-    let mut entries = Vec::<ViewEntry<'a>>::new();
-    for _ in 0..header.entry_count.get() {
-        let entry_header = reader.take_obj_front::<EntryHeader>()?;
-        let name = take_name(&mut reader)?;
-        entries.push(ViewEntry::<'a> {
-            header: entry_header,
-            name,
-        });
-    }
-    let footer = reader.take_front(reader.len())?;
-    Some(View::<'a> {
+}
+
+// git's offset encoding: 7 bits per byte with the high bit meaning more bytes follow, and one
+// added on each continuation so that every value has a single encoding
+fn decode_varint(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut val = 0usize;
+    for (i, &c) in bytes.iter().enumerate() {
+        if i > 0 {
+            val = val.checked_add(1)?.checked_mul(128)?;
+        }
+        val += (c & 0x7f) as usize;
+        if c & 0x80 == 0 {
+            return Some((val, i + 1));
+        }
+    }
+    None
+}
+
+// version 4 names are a varint count of bytes to strip from the end of the previous name,
+// followed by the NUL terminated suffix to append, with no padding
+fn decode_compressed_name(
+    bytes: &[u8],
+    previous: &[u8],
+) -> Result<(Vec<u8>, usize), ParseError> {
+    let (strip_len, varint_size) = decode_varint(bytes).ok_or(ParseError::BadName)?;
+    let keep_len = previous
+        .len()
+        .checked_sub(strip_len)
+        .ok_or(ParseError::BadName)?;
+    let suffix = &bytes[varint_size..];
+    let nul_pos = suffix
+        .iter()
+        .position(|&x| x == b'\0')
+        .ok_or(OUT_OF_BYTES)?;
+    let mut name = previous[..keep_len].to_vec();
+    name.extend_from_slice(&suffix[..nul_pos]);
+    Ok((name, varint_size + nul_pos + 1))
+}
+
+// inverse of decode_varint
+fn encode_varint(mut value: usize, out: &mut Vec<u8>) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        bytes.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+fn encode_compressed_name(name: &[u8], previous: &[u8], out: &mut Vec<u8>) {
+    let common_len = name
+        .iter()
+        .zip(previous.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    encode_varint(previous.len() - common_len, out);
+    out.extend_from_slice(&name[common_len..]);
+    out.push(b'\0');
+}
+
+fn take_compressed_name(reader: &mut SliceReader, previous: &[u8]) -> Result<Vec<u8>, ParseError> {
+    let (name, size) = decode_compressed_name(reader.iter().as_slice(), previous)?;
+    reader.take_front(size).ok_or(OUT_OF_BYTES)?;
+    Ok(name)
+}
+
+fn take_compressed_name_mut<'a>(
+    reader: &mut SliceReaderMut<'a>,
+    previous: &[u8],
+) -> Result<(Vec<u8>, &'a [u8]), ParseError> {
+    let (name, size) = decode_compressed_name(reader.iter().as_slice(), previous)?;
+    let raw_bytes = reader.take_front(size).ok_or(OUT_OF_BYTES)?;
+    Ok((name, raw_bytes))
+}
+
+pub fn parse<'a>(bin: &'a [u8]) -> Result<View<'a>, ParseError> {
+    parse_with_format::<Sha1Format>(bin)
+}
+
+pub fn parse_mut<'a>(bin: &'a mut [u8]) -> Result<ViewMut<'a>, ParseError> {
+    parse_mut_with_format::<Sha1Format>(bin)
+}
+
+// Reads a whole index from r and parses it into an OwnedView, so there is no buffer to keep
+// around. A ParseError comes back as an InvalidData error wrapping it.
+pub fn parse_reader<R: Read>(r: R) -> IoResult<OwnedView> {
+    parse_reader_with_format::<Sha1Format, R>(r)
+}
+
+pub fn parse_reader_with_format<F: ObjectFormat, R: Read>(mut r: R) -> IoResult<OwnedView<F>> {
+    let mut bin = Vec::new();
+    r.read_to_end(&mut bin)?;
+    let view = parse_with_format::<F>(&bin).map_err(|e| IoError::new(ErrorKind::InvalidData, e))?;
+    Ok(OwnedView::from(&view))
+}
+
+pub fn parse_with_format<'a, F: ObjectFormat>(bin: &'a [u8]) -> Result<View<'a, F>, ParseError> {
+    check_file::<F>(bin)?;
+    match parse_entries::<F>(bin)? {
+        (view, None) => Ok(view),
+        (_, Some(e)) => Err(e),
+    }
+}
+
+// Forensic counterpart of parse, for debugging corrupt downloads. Instead of failing on the first
+// problem it returns the entries read up to that point, with the problem alongside. A bad
+// checksum is reported ahead of anything found in the entries, since it means they can't be
+// trusted. Only a file that doesn't even have a usable header is an error.
+pub fn parse_lenient<'a>(bin: &'a [u8]) -> Result<(View<'a>, Option<ParseError>), ParseError> {
+    parse_lenient_with_format::<Sha1Format>(bin)
+}
+
+pub fn parse_lenient_with_format<'a, F: ObjectFormat>(
+    bin: &'a [u8],
+) -> Result<(View<'a, F>, Option<ParseError>), ParseError> {
+    let checksum_error = match check_file::<F>(bin) {
+        Err(ParseError::BadChecksum) => Some(ParseError::BadChecksum),
+        r => r.map(|_| None)?,
+    };
+    let (view, error) = parse_entries::<F>(bin)?;
+    Ok((view, checksum_error.or(error)))
+}
+
+// Reads up to the declared number of entries, stopping at the first one that doesn't parse, and
+// returns what was read along with why it stopped. Without an error, the rest of the file is
+// also checked to be extensions followed by the checksum.
+fn parse_entries<'a, F: ObjectFormat>(
+    bin: &'a [u8],
+) -> Result<(View<'a, F>, Option<ParseError>), ParseError> {
+    let mut reader = SliceReader(bin);
+    let header = reader.take_obj_front::<FileHeader>().ok_or(OUT_OF_BYTES)?;
+    let version = header.version.get();
+    let declared = header.entry_count.get();
+    let mut entries = Vec::<ViewEntry<'a, F>>::new();
+    let mut error = None;
+    for parsed in 0..declared {
+        let mut entry_reader = SliceReader(reader.0);
+        let previous = entries.last().map_or(&[][..], |e| &e.name[..]);
+        match take_entry::<F>(&mut entry_reader, version, previous) {
+            // entries must not run into the checksum
+            Ok(entry) if entry_reader.len() >= hash_size::<F>() => {
+                entries.push(entry);
+                reader = entry_reader;
+                continue;
+            }
+            Ok(_) => error = Some(ParseError::Truncated { parsed, declared }),
+            // if what's left is extensions, the declared count is too high
+            Err(_) if is_extension_list(split_footer::<F>(reader.0).0) => {
+                error = Some(ParseError::Truncated { parsed, declared });
+            }
+            Err(e) => error = Some(with_counts(e, parsed, declared)),
+        }
+        break;
+    }
+    let footer = reader.0;
+    if error.is_none() && !is_extension_list(split_footer::<F>(footer).0) {
+        error = Some(ParseError::BadExtensions);
+    }
+    let view = View::<'a, F> {
         header,
         entries,
         footer,
+    };
+    Ok((view, error))
+}
+
+fn take_entry<'a, F: ObjectFormat>(
+    reader: &mut SliceReader<'a>,
+    version: u32,
+    previous: &[u8],
+) -> Result<ViewEntry<'a, F>, ParseError> {
+    let entry_header = reader
+        .take_obj_front::<F::EntryHeader>()
+        .ok_or(OUT_OF_BYTES)?;
+    let mut header_size = mem::size_of::<F::EntryHeader>();
+    let extended_flags = if has_extended_flags(version, entry_header) {
+        header_size += mem::size_of::<U16<NetworkEndian>>();
+        let extended_flags = reader
+            .take_obj_front::<U16<NetworkEndian>>()
+            .ok_or(OUT_OF_BYTES)?;
+        Some(extended_flags.get())
+    } else {
+        None
+    };
+    let name = if version >= 4 {
+        Cow::Owned(take_compressed_name(reader, previous)?)
+    } else {
+        Cow::Borrowed(take_name(reader, header_size)?)
+    };
+    check_name_length(entry_header, &name)?;
+    Ok(ViewEntry::<'a, F> {
+        header: entry_header,
+        extended_flags,
+        name,
     })
 }
 
-pub fn parse_mut<'a>(bin: &'a mut [u8]) -> Option<ViewMut<'a>> {
-    let mut reader = SliceReaderMut(bin);
-    let header = reader.take_obj_front::<FileHeader>()?;
-    if header.version.get() != 2 {
-        return None;
+// each extension is a header giving its size followed by that many bytes, with nothing left over
+fn is_extension_list(extensions: &[u8]) -> bool {
+    let mut reader = SliceReader(extensions);
+    while reader.len() > 0 {
+        let size = match reader.take_obj_front::<ExtensionHeader>() {
+            Some(header) => header.size.get() as usize,
+            None => return false,
+        };
+        if reader.take_front(size).is_none() {
+            return false;
+        }
     }
-    let mut entries = Vec::<ViewEntryMut<'a>>::new();
+    true
+}
+
+pub fn parse_mut_with_format<'a, F: ObjectFormat>(
+    bin: &'a mut [u8],
+) -> Result<ViewMut<'a, F>, ParseError> {
+    check_file::<F>(bin)?;
+    // same checks as parse, so the mutable parse below can't fail
+    if let (_, Some(e)) = parse_entries::<F>(bin)? {
+        return Err(e);
+    }
+    let mut reader = SliceReaderMut(bin);
+    let header = reader.take_obj_front::<FileHeader>().ok_or(OUT_OF_BYTES)?;
+    let version = header.version.get();
+    let mut entries = Vec::<ViewEntryMut<'a, F>>::new();
     for _ in 0..header.entry_count.get() {
-        let entry_header = reader.take_obj_front::<EntryHeader>()?;
-        let name = take_name_mut(&mut reader)?;
-        entries.push(ViewEntryMut::<'a> {
-            header: entry_header,
-            name,
-        });
-    }
-    let footer = reader.take_front(reader.len())?;
-    Some(ViewMut::<'a> {
+        let previous = entries.last().map_or(&[][..], |e| &e.name[..]);
+        entries.push(take_entry_mut::<F>(&mut reader, version, previous)?);
+    }
+    let footer = reader.take_front(reader.len()).ok_or(OUT_OF_BYTES)?;
+    Ok(ViewMut::<'a, F> {
         header,
         entries,
         footer,
     })
 }
+
+fn take_entry_mut<'a, F: ObjectFormat>(
+    reader: &mut SliceReaderMut<'a>,
+    version: u32,
+    previous: &[u8],
+) -> Result<ViewEntryMut<'a, F>, ParseError> {
+    let entry_header = reader
+        .take_obj_front::<F::EntryHeader>()
+        .ok_or(OUT_OF_BYTES)?;
+    let mut header_size = mem::size_of::<F::EntryHeader>();
+    let extended_flags = if has_extended_flags(version, entry_header) {
+        header_size += mem::size_of::<U16<NetworkEndian>>();
+        let extended_flags = reader
+            .take_obj_front::<U16<NetworkEndian>>()
+            .ok_or(OUT_OF_BYTES)?;
+        Some(extended_flags)
+    } else {
+        None
+    };
+    let (name, raw_name) = if version >= 4 {
+        let (name, raw_name) = take_compressed_name_mut(reader, previous)?;
+        (Cow::Owned(name), raw_name)
+    } else {
+        let (name, raw_name) = take_name_mut(reader, header_size)?;
+        (Cow::Borrowed(name), raw_name)
+    };
+    check_name_length(entry_header, &name)?;
+    Ok(ViewEntryMut::<'a, F> {
+        header: entry_header,
+        extended_flags,
+        name,
+        raw_name,
+    })
+}
+
+// Serializes view into out, laid out the same way parse reads it back. The entry count is taken
+// from view.entries rather than view.header. Extensions such as TREE are copied through byte for
+// byte, and only the checksum after them is recomputed.
+pub fn write<F: ObjectFormat>(view: &View<F>, out: &mut Vec<u8>) {
+    write_impl(view, out, false)
+}
+
+// Like write, but with the volatile fields of every entry zeroed, so indices of the same content
+// come out byte-identical wherever they were built.
+pub fn write_normalized<F: ObjectFormat>(view: &View<F>, out: &mut Vec<u8>) {
+    write_impl(view, out, true)
+}
+
+fn write_impl<F: ObjectFormat>(view: &View<F>, out: &mut Vec<u8>, normalize: bool) {
+    let start = out.len();
+    let version = view.header.version.get();
+    out.extend_from_slice(&view.header.magic);
+    out.extend_from_slice(view.header.version.as_bytes());
+    out.extend_from_slice(U32::<NetworkEndian>::new(view.entries.len() as u32).as_bytes());
+    let mut previous: &[u8] = &[];
+    for entry in view.entries.iter() {
+        if normalize {
+            let mut header = entry.header.clone();
+            header.normalize();
+            out.extend_from_slice(header.as_bytes());
+        } else {
+            out.extend_from_slice(entry.header.as_bytes());
+        }
+        let mut header_size = mem::size_of::<F::EntryHeader>();
+        if has_extended_flags(version, entry.header) {
+            header_size += mem::size_of::<U16<NetworkEndian>>();
+            let extended_flags = entry.extended_flags.unwrap_or(0);
+            out.extend_from_slice(U16::<NetworkEndian>::new(extended_flags).as_bytes());
+        }
+        if version >= 4 {
+            encode_compressed_name(&entry.name, previous, out);
+        } else {
+            let size = round_up(entry.name.len() + header_size + 1, 8) - header_size;
+            out.extend_from_slice(&entry.name);
+            out.resize(out.len() + size - entry.name.len(), b'\0');
+        }
+        previous = &entry.name;
+    }
+    out.extend_from_slice(view.extensions());
+    let checksum = F::Digest::digest(&out[start..]);
+    out.extend_from_slice(&checksum);
+}
+
+// The directories that directly contain an entry, as the name up to its last '/'. Entries at the
+// root aren't in any directory, and creating each of these with all its parents covers the tree.
+pub fn directories<'v, F: ObjectFormat>(view: &'v View<'_, F>) -> BTreeSet<&'v [u8]> {
+    view.entries
+        .iter()
+        .filter_map(|e| {
+            let slash_pos = e.name.iter().rposition(|&x| x == b'/')?;
+            Some(&e.name[..slash_pos])
+        })
+        .collect()
+}
+
+fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// lower case, as git and the CDN's object paths use
+pub fn hash_to_hex(hash: &Hash) -> String {
+    bytes_to_hex(hash)
+}
+
+// Accepts either case, but only exactly 40 digits.
+pub fn hash_from_hex(s: &str) -> Result<Hash, HexError> {
+    if s.len() != mem::size_of::<Hash>() * 2 {
+        return Err(HexError::BadLength);
+    }
+    // from_str_radix would also take a sign
+    if !s.bytes().all(|x| x.is_ascii_hexdigit()) {
+        return Err(HexError::BadDigit);
+    }
+    let mut hash = Hash::default();
+    for (i, b) in hash.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| HexError::BadDigit)?;
+    }
+    Ok(hash)
+}
+
+fn take_until<'a>(reader: &mut SliceReader<'a>, delimiter: u8) -> Option<&'a [u8]> {
+    let pos = reader.iter().position(|&x| x == delimiter)?;
+    Some(&reader.take_front(pos + 1)?[..pos])
+}
+
+fn take_decimal<T: std::str::FromStr>(reader: &mut SliceReader, delimiter: u8) -> Option<T> {
+    std::str::from_utf8(take_until(reader, delimiter)?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn take_tree(reader: &mut SliceReader) -> Option<TreeCache> {
+    let path = take_until(reader, b'\0')?.to_vec();
+    let entry_count = take_decimal::<i64>(reader, b' ')?;
+    let subtree_count = take_decimal::<usize>(reader, b'\n')?;
+    // an entry count of -1 marks the tree as invalidated, and there is no hash following it
+    let (entry_count, hash) = if entry_count < 0 {
+        (None, None)
+    } else {
+        let hash = *reader.take_obj_front::<Hash>()?;
+        (Some(u32::try_from(entry_count).ok()?), Some(hash))
+    };
+    let subtrees = (0..subtree_count)
+        .map(|_| take_tree(reader))
+        .collect::<Option<Vec<_>>>()?;
+    Some(TreeCache {
+        path,
+        entry_count,
+        hash,
+        subtrees,
+    })
+}
+
+// the signature and data of the next extension
+fn take_extension<'a>(reader: &mut SliceReader<'a>) -> Option<([u8; 4], &'a [u8])> {
+    let header = reader.take_obj_front::<ExtensionHeader>()?;
+    let data = reader.take_front(header.size.get() as usize)?;
+    Some((header.signature, data))
+}
+
+fn find_extension<'a>(extensions: &'a [u8], signature: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut reader = SliceReader(extensions);
+    while reader.len() > 0 {
+        let (extension_signature, data) = take_extension(&mut reader)?;
+        if &extension_signature == signature {
+            return Some(data);
+        }
+    }
+    None
+}
+
+// Parses the TREE extension out of the footer of a SHA-1 index, returning None if there isn't one
+// or it is malformed.
+pub fn parse_tree_extension(footer: &[u8]) -> Option<TreeCache> {
+    let extensions = split_footer::<Sha1Format>(footer).0;
+    let mut reader = SliceReader(find_extension(extensions, b"TREE")?);
+    let tree = take_tree(&mut reader)?;
+    if reader.len() != 0 {
+        return None;
+    }
+    Some(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a version 2 index with a file entry for each name, kept in the order given
+    fn build_index(names: &[&str], extensions: &[u8]) -> Vec<u8> {
+        let mut index = OwnedView::<Sha1Format> {
+            header: FileHeader {
+                magic: *b"DIRC",
+                version: U32::new(2),
+                entry_count: U32::new(0),
+            },
+            entries: Vec::new(),
+            // write recomputes the checksum, so only its space matters
+            footer: [extensions, &Hash::default()].concat(),
+        };
+        for name in names.iter() {
+            let mut header = EntryHeader::default();
+            header.mode.set(0o100644);
+            header.flags.set(name.len() as u16);
+            index.entries.push(OwnedEntry {
+                header,
+                extended_flags: None,
+                name: name.as_bytes().to_vec(),
+            });
+        }
+        let mut out = Vec::new();
+        write(&index.view(), &mut out);
+        out
+    }
+
+    fn patch_entry(bin: &mut [u8], finalize: bool) {
+        let mut view = parse_mut(bin).unwrap();
+        let entry = view.find_mut(b"b/c").unwrap();
+        entry.header.size.set(5);
+        entry.header.sha1 = [1; 20];
+        if finalize {
+            view.finalize_checksum();
+        }
+    }
+
+    // header and entries as laid out by hand, followed by their checksum
+    fn with_checksum(parts: &[&[u8]]) -> Vec<u8> {
+        let mut bin = parts.concat();
+        let checksum = Sha1::digest(&bin);
+        bin.extend_from_slice(&checksum);
+        bin
+    }
+
+    fn file_header(version: u32, entry_count: u32) -> Vec<u8> {
+        let counts = [version.to_be_bytes(), entry_count.to_be_bytes()].concat();
+        [&b"DIRC"[..], &counts].concat()
+    }
+
+    #[test]
+    fn version_3_round_trip() {
+        let mut extended = EntryHeader::default();
+        extended.mode.set(0o100644);
+        extended.flags.set(FLAG_EXTENDED | 1);
+        let mut plain = extended.clone();
+        plain.flags.set(2);
+        // skip-worktree in the extended flags, which push the name along by 2 bytes
+        let bin = with_checksum(&[
+            &file_header(3, 2),
+            extended.as_bytes(),
+            &0x4000u16.to_be_bytes(),
+            b"a\0\0\0\0\0\0\0",
+            plain.as_bytes(),
+            b"bc\0\0\0\0\0\0\0\0",
+        ]);
+        let view = parse(&bin).unwrap();
+        assert_eq!(view.entries.len(), 2);
+        assert_eq!(view.entries[0].extended_flags, Some(0x4000));
+        assert_eq!(&view.entries[0].name[..], b"a");
+        assert_eq!(view.entries[1].extended_flags, None);
+        assert_eq!(&view.entries[1].name[..], b"bc");
+        let mut out = Vec::new();
+        write(&view, &mut out);
+        assert_eq!(out, bin);
+    }
+
+    #[test]
+    fn version_4_round_trip() {
+        let header = |name_length| {
+            let mut header = EntryHeader::default();
+            header.mode.set(0o100644);
+            header.flags.set(name_length);
+            header
+        };
+        // each name strips bytes off the previous one and appends a suffix, unpadded: nothing
+        // for the first, a shared "a/" prefix, a zero-length strip, and all of "a/cd" stripped
+        let bin = with_checksum(&[
+            &file_header(4, 4),
+            header(3).as_bytes(),
+            b"\x00a/b\0",
+            header(3).as_bytes(),
+            b"\x01c\0",
+            header(4).as_bytes(),
+            b"\x00d\0",
+            header(1).as_bytes(),
+            b"\x04b\0",
+        ]);
+        let view = parse(&bin).unwrap();
+        let names: Vec<&[u8]> = view.entries.iter().map(|e| &e.name[..]).collect();
+        assert_eq!(names, [&b"a/b"[..], b"a/c", b"a/cd", b"b"]);
+        let mut out = Vec::new();
+        write(&view, &mut out);
+        assert_eq!(out, bin);
+    }
+
+    #[test]
+    fn flags_word() {
+        let mut header = EntryHeader::default();
+        header
+            .flags
+            .set(FLAG_ASSUME_VALID | (2 << STAGE_SHIFT) | 0x123);
+        assert!(header.assume_valid());
+        assert!(!header.extended());
+        assert_eq!(header.stage(), 2);
+        assert_eq!(header.name_length(), 0x123);
+
+        header.flags.set(FLAG_EXTENDED | (1 << STAGE_SHIFT) | 7);
+        assert!(!header.assume_valid());
+        assert!(header.extended());
+        assert_eq!(header.stage(), 1);
+        assert_eq!(header.name_length(), 7);
+
+        // every bit set, so none of the others can leak into the name length or stage
+        header.flags.set(0xffff);
+        assert!(header.assume_valid());
+        assert!(header.extended());
+        assert_eq!(header.stage(), 3);
+        assert_eq!(header.name_length(), NAME_LENGTH_MASK);
+
+        header.flags.set(0);
+        assert!(!header.assume_valid());
+        assert!(!header.extended());
+        assert_eq!(header.stage(), 0);
+        assert_eq!(header.name_length(), 0);
+    }
+
+    #[test]
+    fn finalize_checksum_after_patch() {
+        let mut unfinalized = build_index(&["a", "b/c"], &[]);
+        patch_entry(&mut unfinalized, false);
+        assert_eq!(parse(&unfinalized).unwrap_err(), ParseError::BadChecksum);
+
+        let mut bin = build_index(&["a", "b/c"], &[]);
+        patch_entry(&mut bin, true);
+        let (data, checksum) = bin.split_at(bin.len() - mem::size_of::<Hash>());
+        assert_eq!(Sha1::digest(data)[..], *checksum);
+        let view = parse(&bin).unwrap();
+        let entry = view.find(b"b/c").unwrap();
+        assert_eq!(entry.header.size.get(), 5);
+        assert_eq!(entry.header.sha1, [1; 20]);
+    }
+
+    #[test]
+    fn corrupt_body_fails_checksum() {
+        let bin = build_index(&["a", "b/c"], &[]);
+        for i in [20, bin.len() / 2, bin.len() - mem::size_of::<Hash>() - 1] {
+            let mut corrupt = bin.clone();
+            corrupt[i] ^= 1;
+            assert_eq!(parse(&corrupt).unwrap_err(), ParseError::BadChecksum);
+            assert_eq!(
+                parse_mut(&mut corrupt).unwrap_err(),
+                ParseError::BadChecksum
+            );
+        }
+    }
+
+    #[test]
+    fn find_among_similar_names() {
+        // sorted as git would, foo < foo.c < foo/bar because '.' (0x2E) < '/' (0x2F)
+        let bin = build_index(&["fo", "foo", "foo.c", "foo/bar", "foo0"], &[]);
+        let view = parse(&bin).unwrap();
+        assert!(view.is_sorted());
+        for name in ["fo", "foo", "foo.c", "foo/bar", "foo0"] {
+            let entry = view.find(name.as_bytes()).unwrap();
+            assert_eq!(&entry.name[..], name.as_bytes());
+        }
+        for name in ["", "f", "foo/", "foo/b", "foo/bar/baz", "foo.", "g"] {
+            assert!(view.find(name.as_bytes()).is_none());
+        }
+    }
+
+    #[test]
+    fn find_all_stages() {
+        let mut bin = build_index(&["a", "b", "b", "b", "c"], &[]);
+        let mut view = parse_mut(&mut bin).unwrap();
+        for (stage, entry) in view.entries[1..4].iter_mut().enumerate() {
+            let flags = &mut entry.header.flags;
+            flags.set(flags.get() | ((stage as u16 + 1) << STAGE_SHIFT));
+        }
+        let stages: Vec<u8> = view
+            .find_all_mut(b"b")
+            .iter()
+            .map(|e| e.header.stage())
+            .collect();
+        assert_eq!(stages, [1, 2, 3]);
+        assert_eq!(view.find_mut(b"b").unwrap().header.stage(), 1);
+        assert_eq!(view.find_all_mut(b"a").len(), 1);
+        assert!(view.find_all_mut(b"bb").is_empty());
+        assert!(view.find_all_mut(b"d").is_empty());
+    }
+
+    #[test]
+    fn insert_keeps_entries_sorted() {
+        let bin = build_index(&["a", "b/c", "d"], &[]);
+        let mut owned = OwnedView::from(&parse(&bin).unwrap());
+        for (name, count) in [("b.c", 4), ("0", 5), ("b/a", 6), ("e", 7)] {
+            let mut header = EntryHeader::default();
+            header.size.set(1);
+            assert!(owned.insert_entry(header, name.as_bytes()).is_none());
+            assert_eq!(owned.entries.len(), count);
+            assert_eq!(owned.header.entry_count.get(), count as u32);
+            assert!(owned.is_sorted());
+            let entry = owned.find(name.as_bytes()).unwrap();
+            assert_eq!(entry.header.name_length() as usize, name.len());
+        }
+
+        // an existing name is replaced rather than added twice
+        let mut header = EntryHeader::default();
+        header.size.set(2);
+        let replaced = owned.insert_entry(header, b"b/c").unwrap();
+        assert_eq!(replaced.header.size.get(), 0);
+        assert_eq!(owned.entries.len(), 7);
+        assert_eq!(owned.header.entry_count.get(), 7);
+        assert_eq!(owned.find(b"b/c").unwrap().header.size.get(), 2);
+        let names: Vec<&[u8]> = owned.entries.iter().map(|e| &e.name[..]).collect();
+        assert_eq!(names, [&b"0"[..], b"a", b"b.c", b"b/a", b"b/c", b"d", b"e"]);
+    }
+
+    #[test]
+    fn long_name_round_trip() {
+        let long_name = [&b"d/"[..], &[b'x'; 5000]].concat();
+        for version in [2, 4] {
+            let mut owned = OwnedView::from(&parse(&build_index(&["a", "e"], &[])).unwrap());
+            owned.header.version.set(version);
+            owned.insert_entry(EntryHeader::default(), &long_name);
+            let mut bin = Vec::new();
+            write(&owned.view(), &mut bin);
+
+            // the flags can't hold the length, so they have the mask and the NUL ends the name
+            let view = parse(&bin).unwrap();
+            let entry = view.find(&long_name).unwrap();
+            assert_eq!(entry.header.name_length(), NAME_LENGTH_MASK);
+            assert_eq!(entry.name.len(), 5002);
+            assert_eq!(&view.entries[2].name[..], b"e");
+            let mut out = Vec::new();
+            write(&view, &mut out);
+            assert_eq!(out, bin);
+        }
+    }
+
+    #[test]
+    fn directories_of_entries() {
+        let bin = build_index(&["a/b/c", "a/b/d", "a/e", "f", "g/h"], &[]);
+        let view = parse(&bin).unwrap();
+        let dirs: Vec<&[u8]> = directories(&view).into_iter().collect();
+        // each directory once, and nothing for the root level f
+        assert_eq!(dirs, [&b"a"[..], b"a/b", b"g"]);
+    }
+
+    #[test]
+    fn hash_hex_round_trip() {
+        let hex = "45b983be36b73c0788dc9cbcb76cbb80fc7bb057";
+        let hash = hash_from_hex(hex).unwrap();
+        assert_eq!(hash[..3], [0x45, 0xb9, 0x83]);
+        assert_eq!(hash_to_hex(&hash), hex);
+        // upper case is accepted, but always written back in lower case
+        assert_eq!(hash_from_hex(&hex.to_uppercase()), Ok(hash));
+    }
+
+    #[test]
+    fn hash_hex_rejects_bad_input() {
+        let hex = "45b983be36b73c0788dc9cbcb76cbb80fc7bb057";
+        let long = format!("{}0", hex);
+        let bad_digit = hex.replace('4', "g");
+        let signed = format!("+{}", &hex[1..]);
+        assert_eq!(hash_from_hex(&hex[..39]), Err(HexError::BadLength));
+        assert_eq!(hash_from_hex(&long), Err(HexError::BadLength));
+        assert_eq!(hash_from_hex(""), Err(HexError::BadLength));
+        assert_eq!(hash_from_hex(&bad_digit), Err(HexError::BadDigit));
+        assert_eq!(hash_from_hex(&signed), Err(HexError::BadDigit));
+    }
+
+    fn extension(signature: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        [&signature[..], &(data.len() as u32).to_be_bytes(), data].concat()
+    }
+
+    #[test]
+    fn write_keeps_extensions() {
+        // a root tree of 2 entries with a subtree b of 1, then one git doesn't know
+        let tree = [&b"\x002 1\n"[..], &[1; 20], b"b\x001 0\n", &[2; 20]].concat();
+        let extensions = [extension(b"TREE", &tree), extension(b"ZZZZ", b"unknown")].concat();
+        let bin = build_index(&["a", "b/c"], &extensions);
+        let view = parse(&bin).unwrap();
+        assert_eq!(view.extensions(), &extensions[..]);
+        let mut out = Vec::new();
+        write(&view, &mut out);
+        assert_eq!(out, bin);
+
+        // the layout changes with an added entry, and the root tree is no longer valid, but the
+        // subtree and the unknown extension still come through as is
+        let mut owned = OwnedView::from(&view);
+        let mut header = EntryHeader::default();
+        header.mode.set(0o100644);
+        owned.insert_entry(header, b"d");
+        let mut out = Vec::new();
+        write(&owned.view(), &mut out);
+        let view = parse(&out).unwrap();
+        assert_eq!(view.entries.len(), 3);
+        let tree = [&b"\0-1 1\n"[..], b"b\x001 0\n", &[2; 20]].concat();
+        let expected = [extension(b"TREE", &tree), extension(b"ZZZZ", b"unknown")].concat();
+        assert_eq!(view.extensions(), &expected[..]);
+        let tree = parse_tree_extension(view.footer).unwrap();
+        assert_eq!(tree.hash, None);
+        assert_eq!(tree.subtrees[0].path, b"b");
+        assert_eq!(tree.subtrees[0].hash, Some([2; 20]));
+    }
+
+    // the root of 4 entries, with subtrees a of 1 and b of 2, which has a subtree c of 1
+    fn nested_tree() -> Vec<u8> {
+        [
+            &b"\x004 2\n"[..],
+            &[1; 20],
+            b"a\x001 0\n",
+            &[2; 20],
+            b"b\x002 1\n",
+            &[3; 20],
+            b"c\x001 0\n",
+            &[4; 20],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn insert_invalidates_tree() {
+        let extensions = extension(b"TREE", &nested_tree());
+        let bin = build_index(&["a/x", "b/c/y", "b/z", "d"], &extensions);
+        let mut owned = OwnedView::from(&parse(&bin).unwrap());
+        owned.insert_entry(EntryHeader::default(), b"b/x");
+        let tree = parse_tree_extension(&owned.footer).unwrap();
+        // the root and b, which the new entry is in, but not a or b's other subtree c
+        assert_eq!((tree.entry_count, tree.hash), (None, None));
+        let (a, b) = (&tree.subtrees[0], &tree.subtrees[1]);
+        assert_eq!((a.entry_count, a.hash), (Some(1), Some([2; 20])));
+        assert_eq!((b.entry_count, b.hash), (None, None));
+        assert_eq!(b.subtrees[0].path, b"c");
+        assert_eq!(b.subtrees[0].hash, Some([4; 20]));
+        // the checksum space is still there to be overwritten
+        let mut out = Vec::new();
+        write(&owned.view(), &mut out);
+        assert!(parse_tree_extension(parse(&out).unwrap().footer).is_some());
+    }
+
+    #[test]
+    fn remove_invalidates_tree() {
+        let extensions = extension(b"TREE", &nested_tree());
+        let bin = build_index(&["a/x", "b/c/y", "b/z", "d"], &extensions);
+        let mut owned = OwnedView::from(&parse(&bin).unwrap());
+        owned.remove_entry(b"b/c/y").unwrap();
+        let tree = parse_tree_extension(&owned.footer).unwrap();
+        let (a, b) = (&tree.subtrees[0], &tree.subtrees[1]);
+        assert_eq!(tree.hash, None);
+        assert_eq!(a.hash, Some([2; 20]));
+        assert_eq!(b.hash, None);
+        assert_eq!(b.subtrees[0].hash, None);
+
+        // an entry at the root only invalidates the root
+        let mut owned = OwnedView::from(&parse(&bin).unwrap());
+        owned.remove_entry(b"d").unwrap();
+        let tree = parse_tree_extension(&owned.footer).unwrap();
+        assert_eq!(tree.hash, None);
+        assert_eq!(tree.subtrees[0].hash, Some([2; 20]));
+        assert_eq!(tree.subtrees[1].hash, Some([3; 20]));
+        assert_eq!(tree.subtrees[1].subtrees[0].hash, Some([4; 20]));
+
+        // and a TREE extension that doesn't parse is dropped rather than left stale
+        let extensions = [extension(b"TREE", b"junk"), extension(b"ZZZZ", b"unknown")].concat();
+        let bin = build_index(&["a/x", "d"], &extensions);
+        let mut owned = OwnedView::from(&parse(&bin).unwrap());
+        owned.remove_entry(b"d").unwrap();
+        let expected = extension(b"ZZZZ", b"unknown");
+        assert_eq!(owned.view().extensions(), &expected[..]);
+    }
+
+    #[test]
+    fn sorted_entries() {
+        // git's order is byte-wise, so "a.c" comes before "a/b" because '.' (0x2E) < '/' (0x2F)
+        let sorted = build_index(&["a", "a.c", "a/b", "b"], &[]);
+        let view = parse(&sorted).unwrap();
+        assert!(view.is_sorted());
+        assert!(OwnedView::from(&view).is_sorted());
+
+        let swapped = build_index(&["a", "a/b", "a.c", "b"], &[]);
+        let view = parse(&swapped).unwrap();
+        assert!(!view.is_sorted());
+        assert!(!OwnedView::from(&view).is_sorted());
+
+        let duplicated = build_index(&["a", "b", "b"], &[]);
+        assert!(!parse(&duplicated).unwrap().is_sorted());
+
+        // the same name is fine at different stages, lowest first
+        let mut owned = OwnedView::from(&parse(&duplicated).unwrap());
+        let flags = &mut owned.entries[2].header.flags;
+        flags.set(flags.get() | (1 << STAGE_SHIFT));
+        assert!(owned.is_sorted());
+        owned.entries.swap(1, 2);
+        assert!(!owned.is_sorted());
+    }
+}
@@ -1,14 +1,80 @@
 use std::cmp::min;
-use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::io::{copy, Chain, Error, ErrorKind, Read, Result, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
 
-use flate2::read::{ZlibDecoder, ZlibEncoder};
-use flate2::Compression;
+use async_compression::tokio::bufread::{
+    ZlibDecoder as ZlibDecoderAsync, ZlibEncoder as ZlibEncoderAsync,
+};
+use async_compression::Level;
+use flate2::read::{
+    DeflateDecoder, DeflateEncoder, GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder,
+};
+use flate2::write::ZlibEncoder as ZlibEncoderWrite;
 use sha1::Sha1;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader as BufReaderAsync, ReadBuf};
 
+use crate::git_index::Hash;
+
+pub use flate2::Compression;
 pub use sha1::Digest;
 
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ObjectType {
+    Blob,
+    Commit,
+    Tag,
+    Tree,
+}
+
+// "commit " plus the digits of the largest u64, with room to spare
+const MAX_HEADER_LEN: usize = 32;
+const HASH_ASYNC_BUF_LEN: usize = 64 * 1024;
+
 pub struct GitObjectReadSync<R: Read> {
-    header_skipped: bool,
+    header_read: bool,
+    // header start to expect in place of an object type and space, for custom object stores
+    prefix: Option<Vec<u8>>,
+    // payload bytes still expected according to the header
+    remaining: u64,
+    // payload bytes that were read along with the header
+    pending: U8ReadSync,
+    // largest declared size that will be decoded
+    max_size: u64,
+    r: R,
+}
+
+pub struct GitObjectReadAsync<R: AsyncRead + Unpin> {
+    // header bytes seen so far, up to the NUL
+    header: Vec<u8>,
+    object_type: Option<ObjectType>,
+    remaining: u64,
+    r: R,
+}
+
+// Encodes like encode_sync, while hashing the uncompressed object on the way through.
+pub struct GitObjectEncodeHashSync<R: Read> {
+    encoder: HashEncoder<Chain<U8ReadSync, R>>,
+}
+
+// the container the hashed object is compressed into
+enum HashEncoder<R: Read> {
+    Gzip(GzEncoder<HashReadSync<R>>),
+    Zlib(ZlibEncoder<HashReadSync<R>>),
+}
+
+// Encodes like encode_sync, for content that is written in rather than read. finish has to be
+// called at the end, since dropping it can lose the end of the stream without any error.
+pub struct GitObjectEncodeWrite<W: Write> {
+    encoder: ZlibEncoderWrite<W>,
+    // still to be written into the encoder, ahead of the first content
+    header: Option<Vec<u8>>,
+    // content bytes still expected according to the header
+    remaining: u64,
+}
+
+struct HashReadSync<R: Read> {
+    digest: Sha1,
     r: R,
 }
 
@@ -17,29 +83,275 @@ struct U8ReadSync {
     head: usize,
 }
 
+impl ObjectType {
+    pub fn name(self) -> &'static str {
+        use ObjectType::*;
+        match self {
+            Blob => "blob",
+            Commit => "commit",
+            Tag => "tag",
+            Tree => "tree",
+        }
+    }
+
+    fn from_name(name: &[u8]) -> Option<Self> {
+        use ObjectType::*;
+        match name {
+            b"blob" => Some(Blob),
+            b"commit" => Some(Commit),
+            b"tag" => Some(Tag),
+            b"tree" => Some(Tree),
+            _ => None,
+        }
+    }
+}
+
+// parses everything before the NUL that separates the header from the payload
+fn parse_header(header: &[u8]) -> Result<(ObjectType, u64)> {
+    // blobs are by far the most common, so check for them first
+    let (object_type, size) = if let Some(size) = header.strip_prefix(b"blob ") {
+        (ObjectType::Blob, size)
+    } else {
+        let space_pos = header
+            .iter()
+            .position(|&x| x == b' ')
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "git_object bad magic"))?;
+        let object_type = ObjectType::from_name(&header[..space_pos])
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "git_object bad magic"))?;
+        (object_type, &header[space_pos + 1..])
+    };
+    Ok((object_type, parse_size(size)?))
+}
+
+fn parse_size(size: &[u8]) -> Result<u64> {
+    if size.is_empty() || !size.iter().all(|x| x.is_ascii_digit()) {
+        return Err(Error::new(ErrorKind::InvalidData, "git_object bad size"));
+    }
+    std::str::from_utf8(size)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "git_object bad size"))
+}
+
+// checks a payload read of amt bytes against what the header says is left
+fn check_payload(amt: usize, requested: usize, remaining: u64) -> Result<()> {
+    if amt as u64 > remaining {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "git_object longer than declared size",
+        ));
+    }
+    if amt == 0 && requested > 0 && remaining > 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "git_object shorter than declared size",
+        ));
+    }
+    Ok(())
+}
+
+impl<R: Read> GitObjectReadSync<R> {
+    fn new(r: R) -> Self {
+        GitObjectReadSync {
+            header_read: false,
+            prefix: None,
+            remaining: 0,
+            pending: U8ReadSync {
+                buf: Vec::new(),
+                head: 0,
+            },
+            max_size: u64::MAX,
+            r,
+        }
+    }
+
+    fn read_header(&mut self) -> Result<ObjectType> {
+        self.read_header_with(parse_header)
+    }
+
+    fn read_prefixed_header(&mut self, prefix: &[u8]) -> Result<()> {
+        self.read_header_with(|header| {
+            let size = header
+                .strip_prefix(prefix)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "git_object bad magic"))?;
+            Ok(((), parse_size(size)?))
+        })
+    }
+
+    // reads the header up to its NUL and splits it with parse into whatever it describes and the
+    // payload size
+    fn read_header_with<T>(&mut self, parse: impl FnOnce(&[u8]) -> Result<(T, u64)>) -> Result<T> {
+        // room for the longest header plus its NUL, so this is usually a single read
+        let mut buf = [0u8; MAX_HEADER_LEN + 1];
+        let mut filled = 0;
+        let nul_pos = loop {
+            if let Some(pos) = buf[..filled].iter().position(|&x| x == b'\0') {
+                break pos;
+            }
+            if filled == buf.len() {
+                return Err(Error::new(ErrorKind::InvalidData, "git_object bad size"));
+            }
+            match self.r.read(&mut buf[filled..]) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "git_object missing header",
+                    ))
+                }
+                Ok(amt) => filled += amt,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        };
+        let (parsed, size) = parse(&buf[..nul_pos])?;
+        if size > self.max_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "git_object larger than limit",
+            ));
+        }
+        let over_read = &buf[nul_pos + 1..filled];
+        check_payload(over_read.len(), 0, size)?;
+        self.pending = U8ReadSync {
+            buf: over_read.to_vec(),
+            head: 0,
+        };
+        self.remaining = size - over_read.len() as u64;
+        self.header_read = true;
+        Ok(parsed)
+    }
+}
+
 impl<R: Read> Read for GitObjectReadSync<R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        if !self.header_skipped {
-            {
-                let mut buf = [0u8; 5];
-                self.r.read_exact(&mut buf)?;
-                if &buf != b"blob " {
-                    return Err(Error::new(ErrorKind::InvalidData, "git_object bad magic"));
+        if !self.header_read {
+            match self.prefix.take() {
+                Some(prefix) => self.read_prefixed_header(&prefix)?,
+                None => {
+                    self.read_header()?;
                 }
             }
-            loop {
-                let mut buf = [0u8; 1];
-                self.r.read_exact(&mut buf)?;
-                if buf[0] == b'\0' {
-                    break;
-                }
-                if !(buf[0] >= b'0' && buf[0] <= b'9') {
-                    return Err(Error::new(ErrorKind::InvalidData, "git_object bad size"));
-                }
+        }
+        if self.pending.head < self.pending.buf.len() {
+            return self.pending.read(buf);
+        }
+        let amt = self.r.read(buf)?;
+        check_payload(amt, buf.len(), self.remaining)?;
+        self.remaining -= amt as u64;
+        Ok(amt)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for GitObjectReadAsync<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while this.object_type.is_none() {
+            let mut byte = [0u8; 1];
+            let mut byte_buf = ReadBuf::new(&mut byte);
+            ready!(Pin::new(&mut this.r).poll_read(cx, &mut byte_buf))?;
+            if byte_buf.filled().is_empty() {
+                return Poll::Ready(Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "git_object missing header",
+                )));
+            }
+            if byte[0] == b'\0' {
+                let (object_type, size) = parse_header(&this.header)?;
+                this.remaining = size;
+                this.object_type = Some(object_type);
+            } else if this.header.len() >= MAX_HEADER_LEN {
+                return Poll::Ready(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "git_object bad size",
+                )));
+            } else {
+                this.header.push(byte[0]);
             }
-            self.header_skipped = true;
         }
-        self.r.read(buf)
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.r).poll_read(cx, buf))?;
+        let amt = buf.filled().len() - before;
+        if let Err(e) = check_payload(amt, buf.capacity() - before, this.remaining) {
+            // don't hand out any of a bad read
+            buf.set_filled(before);
+            return Poll::Ready(Err(e));
+        }
+        this.remaining -= amt as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: Read> GitObjectEncodeHashSync<R> {
+    // The hash only covers what has been read so far, so read to the end first.
+    pub fn hash(self) -> Hash {
+        let hash_read = match self.encoder {
+            HashEncoder::Gzip(encoder) => encoder.into_inner(),
+            HashEncoder::Zlib(encoder) => encoder.into_inner(),
+        };
+        hash_read.digest.finalize().into()
+    }
+}
+
+impl<R: Read> Read for GitObjectEncodeHashSync<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match &mut self.encoder {
+            HashEncoder::Gzip(encoder) => encoder.read(buf),
+            HashEncoder::Zlib(encoder) => encoder.read(buf),
+        }
+    }
+}
+
+impl<W: Write> GitObjectEncodeWrite<W> {
+    fn write_header(&mut self) -> Result<()> {
+        if let Some(header) = self.header.take() {
+            self.encoder.write_all(&header)?;
+        }
+        Ok(())
+    }
+
+    // Writes out the rest of the stream and returns the sink. Fails with InvalidData if less
+    // content was written than the size given up front.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_header()?;
+        if self.remaining > 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "git_object shorter than declared size",
+            ));
+        }
+        self.encoder.finish()
+    }
+}
+
+impl<W: Write> Write for GitObjectEncodeWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "git_object longer than declared size",
+            ));
+        }
+        self.write_header()?;
+        let amt = self.encoder.write(buf)?;
+        self.remaining -= amt as u64;
+        Ok(amt)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_header()?;
+        self.encoder.flush()
+    }
+}
+
+impl<R: Read> Read for HashReadSync<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let amt = self.r.read(buf)?;
+        self.digest.update(&buf[..amt]);
+        Ok(amt)
     }
 }
 
@@ -53,22 +365,387 @@ impl Read for U8ReadSync {
 }
 
 pub fn encode_sync<'a, R: Read + 'a>(size: u64, read: R) -> impl Read + 'a {
+    encode_sync_with(size, read, Compression::fast())
+}
+
+// Like encode_sync, taking the size from data so it can't disagree with the header.
+pub fn encode_bytes(data: &[u8]) -> impl Read + '_ {
+    encode_sync(data.len() as u64, data)
+}
+
+// The compression level only changes the compressed bytes. The decoded content, and so the git
+// hash of the object, stays the same.
+pub fn encode_sync_with<'a, R: Read + 'a>(size: u64, read: R, level: Compression) -> impl Read + 'a {
     let prefix = U8ReadSync {
         buf: format!("blob {}\0", size).as_bytes().to_vec(),
         head: 0,
     };
-    ZlibEncoder::new(prefix.chain(read), Compression::fast())
+    ZlibEncoder::new(prefix.chain(read), level)
+}
+
+// Like encode_sync, but in a gzip container rather than zlib, for mirrors that serve gzip.
+pub fn encode_sync_gzip<'a, R: Read + 'a>(size: u64, read: R) -> impl Read + 'a {
+    let prefix = U8ReadSync {
+        buf: format!("blob {}\0", size).as_bytes().to_vec(),
+        head: 0,
+    };
+    GzEncoder::new(prefix.chain(read), Compression::fast())
+}
+
+// Like encode_sync, but without the zlib wrapper around the deflate stream.
+pub fn encode_sync_raw<'a, R: Read + 'a>(size: u64, read: R) -> impl Read + 'a {
+    let prefix = U8ReadSync {
+        buf: format!("blob {}\0", size).as_bytes().to_vec(),
+        head: 0,
+    };
+    DeflateEncoder::new(prefix.chain(read), Compression::fast())
+}
+
+pub fn encode_writer<W: Write>(size: u64, sink: W) -> GitObjectEncodeWrite<W> {
+    GitObjectEncodeWrite {
+        encoder: ZlibEncoderWrite::new(sink, Compression::fast()),
+        header: Some(format!("blob {}\0", size).into_bytes()),
+        remaining: size,
+    }
+}
+
+pub fn encode_and_hash_sync<R: Read>(size: u64, read: R) -> GitObjectEncodeHashSync<R> {
+    encode_and_hash_sync_with(size, read, Compression::fast())
+}
+
+pub fn encode_and_hash_sync_with<R: Read>(
+    size: u64,
+    read: R,
+    level: Compression,
+) -> GitObjectEncodeHashSync<R> {
+    let prefix = U8ReadSync {
+        buf: format!("blob {}\0", size).as_bytes().to_vec(),
+        head: 0,
+    };
+    let hash_read = HashReadSync {
+        digest: Sha1::new(),
+        r: prefix.chain(read),
+    };
+    GitObjectEncodeHashSync {
+        encoder: HashEncoder::Zlib(ZlibEncoder::new(hash_read, level)),
+    }
+}
+
+// Like encode_and_hash_sync_with, but in a gzip container like encode_sync_gzip.
+pub fn encode_and_hash_sync_gzip<R: Read>(
+    size: u64,
+    read: R,
+    level: Compression,
+) -> GitObjectEncodeHashSync<R> {
+    let prefix = U8ReadSync {
+        buf: format!("blob {}\0", size).as_bytes().to_vec(),
+        head: 0,
+    };
+    let hash_read = HashReadSync {
+        digest: Sha1::new(),
+        r: prefix.chain(read),
+    };
+    GitObjectEncodeHashSync {
+        encoder: HashEncoder::Gzip(GzEncoder::new(hash_read, level)),
+    }
+}
+
+pub fn encode_async<'a, R: AsyncRead + Unpin + 'a>(size: u64, read: R) -> impl AsyncRead + Unpin + 'a {
+    let prefix = std::io::Cursor::new(format!("blob {}\0", size).into_bytes());
+    let chained = AsyncReadExt::chain(prefix, read);
+    ZlibEncoderAsync::with_quality(BufReaderAsync::new(chained), Level::Fastest)
 }
 
 pub fn decode_sync<'a, R: Read + 'a>(read: R) -> impl Read + 'a {
-    GitObjectReadSync {
-        header_skipped: false,
-        r: ZlibDecoder::new(read),
+    GitObjectReadSync::new(ZlibDecoder::new(read))
+}
+
+// Like decode_sync, but fails with InvalidData if the header declares more than max_bytes of
+// payload. The payload is never allowed past its declared size, so this bounds the whole output.
+pub fn decode_sync_limited<'a, R: Read + 'a>(read: R, max_bytes: u64) -> impl Read + 'a {
+    let mut r = GitObjectReadSync::new(ZlibDecoder::new(read));
+    r.max_size = max_bytes;
+    r
+}
+
+// Like decode_sync, but the header has to start with prefix, such as b"tree " or a custom object
+// store's own token, followed by the size.
+pub fn decode_sync_with_prefix<'a, R: Read + 'a>(read: R, prefix: &[u8]) -> impl Read + 'a {
+    let mut r = GitObjectReadSync::new(ZlibDecoder::new(read));
+    r.prefix = Some(prefix.to_vec());
+    r
+}
+
+// Like decode_sync, but for an object in a gzip container like encode_sync_gzip makes.
+pub fn decode_sync_gzip<'a, R: Read + 'a>(read: R) -> impl Read + 'a {
+    GitObjectReadSync::new(GzDecoder::new(read))
+}
+
+// Like decode_sync, but for a raw deflate stream without the zlib wrapper.
+pub fn decode_sync_raw<'a, R: Read + 'a>(read: R) -> impl Read + 'a {
+    GitObjectReadSync::new(DeflateDecoder::new(read))
+}
+
+// Like decode_sync, but reads the header up front to report the object type and payload size.
+pub fn decode_sync_typed<'a, R: Read + 'a>(read: R) -> Result<(ObjectType, u64, impl Read + 'a)> {
+    let mut r = GitObjectReadSync::new(ZlibDecoder::new(read));
+    let object_type = r.read_header()?;
+    let size = r.remaining + r.pending.buf.len() as u64;
+    Ok((object_type, size, r))
+}
+
+// Like decode_sync, but reads the header up front to report the payload size, e.g. to size the
+// output before copying.
+pub fn decode_sync_with_size<'a, R: Read + 'a>(read: R) -> Result<(u64, impl Read + 'a)> {
+    let (_, size, r) = decode_sync_typed(read)?;
+    Ok((size, r))
+}
+
+pub fn decode_async<'a, R: AsyncRead + Unpin + 'a>(read: R) -> impl AsyncRead + Unpin + 'a {
+    GitObjectReadAsync {
+        header: Vec::new(),
+        object_type: None,
+        remaining: 0,
+        r: ZlibDecoderAsync::new(BufReaderAsync::new(read)),
     }
 }
 
 pub fn hash_sync(size: u64) -> impl Digest<OutputSize = <Sha1 as Digest>::OutputSize> + Write {
+    hash_sync_typed(ObjectType::Blob, size)
+}
+
+pub fn hash_sync_typed(
+    object_type: ObjectType,
+    size: u64,
+) -> impl Digest<OutputSize = <Sha1 as Digest>::OutputSize> + Write {
     let mut state = Sha1::new();
-    state.update(format!("blob {}\0", size).as_bytes());
+    state.update(format!("{} {}\0", object_type.name(), size).as_bytes());
     state
 }
+
+// Hashes the content from read like hash_sync, reading in chunks so it can run on the runtime
+// without spawn_blocking.
+pub async fn hash_async<R: AsyncRead + Unpin>(size: u64, mut read: R) -> Result<Hash> {
+    let mut digest = hash_sync(size);
+    let mut buf = vec![0u8; HASH_ASYNC_BUF_LEN];
+    loop {
+        let amt = read.read(&mut buf).await?;
+        if amt == 0 {
+            break;
+        }
+        digest.update(&buf[..amt]);
+    }
+    Ok(digest.finalize().into())
+}
+
+// Hashes size bytes of content from read like hash_sync. A mismatch is Ok(false), so an Err is
+// always a failure to read.
+pub fn verify_sync<R: Read>(expected: &Hash, size: u64, mut read: R) -> Result<bool> {
+    let mut digest = hash_sync(size);
+    copy(&mut read, &mut digest)?;
+    Ok(digest.finalize()[..] == expected[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // long enough to take several reads through the encoders and decoders
+    fn content() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    // counts the reads made on r and the bytes they return
+    struct CountingRead<R: Read> {
+        reads: usize,
+        bytes: usize,
+        r: R,
+    }
+
+    impl<R: Read> Read for CountingRead<R> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let amt = self.r.read(buf)?;
+            self.reads += 1;
+            self.bytes += amt;
+            Ok(amt)
+        }
+    }
+
+    #[test]
+    fn header_read_is_buffered() {
+        let content = content();
+        let object = [format!("blob {}\0", content.len()).as_bytes(), &content].concat();
+        let mut r = GitObjectReadSync::new(CountingRead {
+            reads: 0,
+            bytes: 0,
+            r: &object[..],
+        });
+
+        let mut start = [0u8; 10];
+        r.read_exact(&mut start).unwrap();
+        assert_eq!(start[..], content[..10]);
+        // a single read for the header, with the start of the payload coming out of what it
+        // over-read
+        assert_eq!(r.r.reads, 1);
+        assert_eq!(r.r.bytes, MAX_HEADER_LEN + 1);
+
+        let mut rest = Vec::new();
+        r.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest[..], content[10..]);
+        assert_eq!(r.r.bytes, object.len());
+    }
+
+    #[test]
+    fn hash_matches_git() {
+        // git hash-object of a file containing "hi\n"
+        let mut digest = hash_sync(3);
+        digest.write_all(b"hi\n").unwrap();
+        assert_eq!(
+            crate::git_index::hash_to_hex(&digest.finalize().into()),
+            "45b983be36b73c0788dc9cbcb76cbb80fc7bb057"
+        );
+        // and of the empty tree, from git hash-object -t tree /dev/null
+        let digest = hash_sync_typed(ObjectType::Tree, 0);
+        assert_eq!(
+            crate::git_index::hash_to_hex(&digest.finalize().into()),
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+    }
+
+    #[tokio::test]
+    async fn encode_async_round_trip() {
+        let content = content();
+        let mut encoded = Vec::new();
+        encode_async(content.len() as u64, &content[..])
+            .read_to_end(&mut encoded)
+            .await
+            .unwrap();
+        let mut decoded = Vec::new();
+        decode_sync(&encoded[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, content);
+    }
+
+    #[test]
+    fn encode_bytes_round_trip() {
+        let content = content();
+        let mut encoded = Vec::new();
+        encode_bytes(&content).read_to_end(&mut encoded).unwrap();
+        let (size, mut decode_read) = decode_sync_with_size(&encoded[..]).unwrap();
+        assert_eq!(size, content.len() as u64);
+        let mut decoded = Vec::new();
+        decode_read.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, content);
+    }
+
+    #[test]
+    fn encode_writer_matches_encode_bytes() {
+        let content = content();
+        let mut writer = encode_writer(content.len() as u64, Vec::new());
+        // in pieces, the way a push-based source would write it
+        for piece in content.chunks(4096) {
+            writer.write_all(piece).unwrap();
+        }
+        let written = writer.finish().unwrap();
+        let mut encoded = Vec::new();
+        encode_bytes(&content).read_to_end(&mut encoded).unwrap();
+        assert_eq!(written, encoded);
+
+        let mut decoded = Vec::new();
+        decode_sync(&written[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, content);
+    }
+
+    #[tokio::test]
+    async fn hash_async_matches_hash_sync() {
+        let content = content();
+        let mut digest = hash_sync(content.len() as u64);
+        digest.write_all(&content).unwrap();
+        let expected: Hash = digest.finalize().into();
+        let hash = hash_async(content.len() as u64, &content[..])
+            .await
+            .unwrap();
+        assert_eq!(hash, expected);
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        ZlibEncoder::new(data, Compression::fast())
+            .read_to_end(&mut encoded)
+            .unwrap();
+        encoded
+    }
+
+    #[test]
+    fn decode_rejects_wrong_size() {
+        let content = content();
+        let mut encoded = Vec::new();
+        encode_bytes(&content).read_to_end(&mut encoded).unwrap();
+        // cut off part way through the payload, or just before the end of the stream
+        for len in [encoded.len() / 2, encoded.len() - 1] {
+            let r = decode_sync(&encoded[..len]).read_to_end(&mut Vec::new());
+            assert!(r.is_err(), "truncated to {} bytes", len);
+        }
+
+        // a complete stream whose payload is shorter than its header says
+        let e = decode_sync(&zlib(b"blob 10\0hello")[..])
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        // or longer
+        let e = decode_sync(&zlib(b"blob 3\0hello")[..])
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let mut decoded = Vec::new();
+        decode_sync(&zlib(b"blob 5\0hello")[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, b"hello");
+    }
+
+    #[test]
+    fn decode_with_prefix() {
+        let encoded = zlib(b"lfs 5\0hello");
+        let mut decoded = Vec::new();
+        decode_sync_with_prefix(&encoded[..], b"lfs ")
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, b"hello");
+
+        let e = decode_sync_with_prefix(&encoded[..], b"blob ")
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        // and without a prefix, only the real object types are accepted
+        let e = decode_sync(&encoded[..])
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let content = content();
+        let mut encoded = Vec::new();
+        encode_sync_gzip(content.len() as u64, &content[..])
+            .read_to_end(&mut encoded)
+            .unwrap();
+        // gzip magic rather than a zlib header
+        assert_eq!(encoded[..2], [0x1f, 0x8b]);
+        let mut decoded = Vec::new();
+        decode_sync_gzip(&encoded[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, content);
+
+        // the hashing encoder makes the same stream, and the hash of the content
+        let mut hash_encoder =
+            encode_and_hash_sync_gzip(content.len() as u64, &content[..], Compression::fast());
+        let mut hash_encoded = Vec::new();
+        hash_encoder.read_to_end(&mut hash_encoded).unwrap();
+        assert_eq!(hash_encoded, encoded);
+        let mut digest = hash_sync(content.len() as u64);
+        digest.write_all(&content).unwrap();
+        assert_eq!(hash_encoder.hash()[..], digest.finalize()[..]);
+    }
+}
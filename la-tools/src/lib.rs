@@ -1,2 +1,3 @@
 pub mod git_index;
 pub mod git_object;
+pub mod spool;
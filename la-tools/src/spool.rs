@@ -0,0 +1,21 @@
+use std::io::{copy, Cursor, Read, Result, Seek, SeekFrom, Write};
+
+// inputs up to this size stay in memory
+const MEMORY_LIMIT: u64 = 64 * 1024 * 1024;
+
+// Reads all of read up front, for when its length has to be known before it can be used, like
+// writing a git object header from a pipe. Larger inputs spill to an anonymous temp file.
+pub fn spool<'a, R: Read + 'a>(mut read: R) -> Result<(u64, Box<dyn Read + 'a>)> {
+    let mut buf = Vec::new();
+    (&mut read).take(MEMORY_LIMIT + 1).read_to_end(&mut buf)?;
+    if buf.len() as u64 <= MEMORY_LIMIT {
+        return Ok((buf.len() as u64, Box::new(Cursor::new(buf))));
+    }
+    let mut f = tempfile::tempfile()?;
+    f.write_all(&buf)?;
+    drop(buf);
+    copy(&mut read, &mut f)?;
+    let size = f.stream_position()?;
+    f.seek(SeekFrom::Start(0))?;
+    Ok((size, Box::new(f)))
+}
@@ -0,0 +1,91 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::read;
+use std::io::{stdin, Read};
+
+use clap::Clap;
+use serde_json::json;
+
+use la_tools::git_index;
+use la_tools::git_index::{entry_json, OutputFormat};
+
+#[derive(Clap)]
+struct Opts {
+    // index to list, or stdin if not given
+    file: Option<String>,
+    // text or json
+    #[clap(long, default_value = "text")]
+    format: String,
+    // list whatever entries can be read from a corrupt index, then exit with 2
+    #[clap(long)]
+    lenient: bool,
+}
+
+pub fn try_main<I, T>(itr: I) -> Result<i32, Box<dyn Error>>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let opts = Opts::parse_from(itr);
+
+    let format = match OutputFormat::from_name(&opts.format) {
+        Some(format) => format,
+        None => {
+            eprintln!("Unknown format {}, must be text or json", opts.format);
+            return Ok(1);
+        }
+    };
+
+    let data = match &opts.file {
+        Some(file_name) => read(file_name)?,
+        None => {
+            let mut data = Vec::<u8>::new();
+            stdin().read_to_end(&mut data)?;
+            data
+        }
+    };
+
+    let parsed = if opts.lenient {
+        git_index::parse_lenient(&data)
+    } else {
+        git_index::parse(&data).map(|index| (index, None))
+    };
+    let (index, error) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Parse error: {}", e);
+            return Ok(2);
+        }
+    };
+
+    let version = index.header.version.get();
+
+    if format == OutputFormat::Json {
+        let entries: Vec<_> = index.entries.iter().map(entry_json).collect();
+        println!(
+            "{}",
+            json!({
+                "version": version,
+                "entry_count": entries.len(),
+                "entries": entries,
+            })
+        );
+    } else {
+        for e in index.entries.iter() {
+            println!("{}", e);
+        }
+        println!("{} entries, version {}", index.entries.len(), version);
+    }
+
+    if let Some(e) = error {
+        eprintln!(
+            "Parse error: {}, read {} of {} entries",
+            e,
+            index.entries.len(),
+            index.header.entry_count.get()
+        );
+        return Ok(2);
+    }
+
+    Ok(0)
+}
@@ -1,36 +1,108 @@
 use std::error::Error;
 use std::ffi::OsString;
-use std::fs::File;
-use std::io::{copy, stdout, Seek, SeekFrom};
+use std::fs::{create_dir_all, rename, write, File};
+use std::io::{copy, stdin, stdout, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 
+use clap::Clap;
+
+use la_tools::git_index;
 use la_tools::git_object;
+use la_tools::git_object::Compression;
+use la_tools::spool::spool;
+
+#[derive(Clap)]
+struct Opts {
+    // - for stdin
+    file: String,
+    // zlib like git, or gzip for mirrors that serve that instead
+    #[clap(long, default_value = "zlib")]
+    format: String,
+    // write the hash to this file instead of stderr
+    #[clap(long)]
+    hash_out: Option<String>,
+    // zlib level from 0 to 9, defaulting to fast
+    #[clap(long)]
+    level: Option<u32>,
+    #[clap(long, conflicts_with = "output-by-hash")]
+    output: Option<String>,
+    // write to <dir>/<first two hex digits>/<rest of the hash>, like the CDN's object layout
+    #[clap(long)]
+    output_by_hash: Option<String>,
+}
 
 pub fn try_main<I, T>(itr: I) -> Result<i32, Box<dyn Error>>
 where
     I: IntoIterator<Item = T>,
     T: Into<OsString> + Clone,
 {
-    let args: Vec<String> = itr
-        .into_iter()
-        .map(|i| i.into().to_string_lossy().into())
-        .collect();
-    if args.len() < 2 {
-        eprintln!("Usage: make-git-object <file>");
-        return Ok(1);
-    }
+    let opts = Opts::parse_from(itr);
 
-    let file_name = &args[1];
-    let file_size = {
-        let mut f = File::open(file_name)?;
-        f.seek(SeekFrom::End(0))?
+    let level = match opts.level {
+        None => Compression::fast(),
+        Some(l) if l <= 9 => Compression::new(l),
+        Some(l) => {
+            eprintln!("Invalid level {}, must be 0 to 9", l);
+            return Ok(1);
+        }
     };
 
-    let f = File::open(file_name)?;
-    let mut git_obj_read = git_object::encode_sync(file_size, f);
+    let gzip = match opts.format.as_str() {
+        "zlib" => false,
+        "gzip" => true,
+        _ => {
+            eprintln!("Unknown format {}, must be zlib or gzip", opts.format);
+            return Ok(1);
+        }
+    };
+
+    let file_name = &opts.file;
+    let (file_size, f): (u64, Box<dyn Read>) = if file_name == "-" {
+        // the header needs the size before any content
+        spool(stdin())?
+    } else {
+        let file_size = {
+            let mut f = File::open(file_name)?;
+            f.seek(SeekFrom::End(0))?
+        };
+        (file_size, Box::new(File::open(file_name)?))
+    };
 
-    let mut out = stdout();
+    let mut git_obj_read = if gzip {
+        git_object::encode_and_hash_sync_gzip(file_size, f, level)
+    } else {
+        git_object::encode_and_hash_sync_with(file_size, f, level)
+    };
+
+    // the hash isn't known until the end, so write somewhere temporary first
+    let tmp_path = opts.output_by_hash.as_ref().map(|dir| {
+        Path::new(dir).join(format!(".make-git-object-{}.tmp", std::process::id()))
+    });
+
+    let mut out: Box<dyn Write> = match (&opts.output, &tmp_path) {
+        (Some(path), _) => Box::new(File::create(path)?),
+        (None, Some(path)) => {
+            create_dir_all(path.parent().unwrap())?;
+            Box::new(File::create(path)?)
+        }
+        (None, None) => Box::new(stdout()),
+    };
 
     copy(&mut git_obj_read, &mut out)?;
+    out.flush()?;
+    drop(out);
+
+    let hash_str = git_index::hash_to_hex(&git_obj_read.hash());
+    match &opts.hash_out {
+        Some(path) => write(path, format!("{}\n", hash_str))?,
+        None => eprintln!("{}", hash_str),
+    }
+
+    if let (Some(dir), Some(tmp_path)) = (&opts.output_by_hash, &tmp_path) {
+        let obj_dir = Path::new(dir).join(&hash_str[..2]);
+        create_dir_all(&obj_dir)?;
+        rename(tmp_path, obj_dir.join(&hash_str[2..]))?;
+    }
 
     Ok(0)
 }
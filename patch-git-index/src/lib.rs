@@ -1,22 +1,125 @@
 use std::error::Error;
 use std::ffi::OsString;
-use std::io::{stdin, stdout, Read, Write};
-
-use hex::FromHex;
+use std::fs::{read, read_to_string};
+use std::io::{stdin, stdout, Read, Result as IoResult, Write};
 
 use la_tools::git_index;
+use la_tools::git_index::{EntryHeader, OwnedView};
 
-fn patch_index(mut b: &mut [u8], name: &[u8], new_size: u32, new_hash: &[u8]) -> Option<()> {
-    let mut index_view = git_index::parse_mut(&mut b)?;
+struct Edit {
+    name: Vec<u8>,
+    size: u32,
+    hash: git_index::Hash,
+}
 
-    for entry in &mut index_view.entries {
-        if entry.name == name {
-            entry.header.size.set(new_size);
-            entry.header.sha1.clone_from_slice(new_hash);
-        }
+const MODE_FILE: u32 = 0o100644;
+
+// Returns whether each edit's name was found in the index. A name with merge conflicts has an
+// entry per stage, and all of them are patched.
+fn patch_index(b: &mut [u8], edits: &[Edit]) -> Result<Vec<bool>, git_index::ParseError> {
+    let mut index_view = git_index::parse_mut(b)?;
+
+    let found = edits
+        .iter()
+        .map(|edit| {
+            let entries = index_view.find_all_mut(&edit.name);
+            for entry in entries.iter_mut() {
+                entry.header.size.set(edit.size);
+                entry.header.sha1.clone_from_slice(&edit.hash);
+            }
+            !entries.is_empty()
+        })
+        .collect();
+
+    index_view.finalize_checksum();
+
+    Ok(found)
+}
+
+// Rewrites the index in b with a new entry for each edit, which should be the ones patch_index
+// didn't find. The entries stay sorted, and only the mode, size and hash of the new ones are set.
+fn add_entries(b: &mut Vec<u8>, edits: &[&Edit]) -> Result<(), git_index::ParseError> {
+    let mut index = OwnedView::from(&git_index::parse(b)?);
+    for edit in edits.iter() {
+        let mut header = EntryHeader::default();
+        header.mode.set(MODE_FILE);
+        header.size.set(edit.size);
+        header.sha1 = edit.hash;
+        index.insert_entry(header, &edit.name);
+    }
+    b.clear();
+    git_index::write(&index.view(), b);
+    Ok(())
+}
+
+// Rewrites the index in b without the entries of the given names, returning how many were found
+// and removed.
+fn remove_entries(b: &mut Vec<u8>, names: &[String]) -> Result<usize, git_index::ParseError> {
+    let mut index = OwnedView::from(&git_index::parse(b)?);
+    let removed = names
+        .iter()
+        .filter(|name| index.remove_entry(name.as_bytes()).is_some())
+        .count();
+    b.clear();
+    git_index::write(&index.view(), b);
+    Ok(removed)
+}
+
+fn parse_edit(name_str: &str, size_str: &str, hash_str: &str) -> Result<Edit, Box<dyn Error>> {
+    Ok(Edit {
+        name: name_str.as_bytes().to_vec(),
+        size: size_str.parse::<u32>()?,
+        hash: git_index::hash_from_hex(hash_str)?,
+    })
+}
+
+// One edit per line as "<name> <size> <hash>". The name is everything before the last two
+// fields, so it can contain spaces.
+fn parse_batch(batch: &str) -> Result<Vec<Edit>, Box<dyn Error>> {
+    batch
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut fields = line.rsplitn(3, ' ');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(hash_str), Some(size_str), Some(name_str)) => {
+                    parse_edit(name_str, size_str, hash_str)
+                }
+                _ => Err(format!("Bad batch line: {}", line).into()),
+            }
+        })
+        .collect()
+}
+
+// from stdin for -
+fn read_text(path: &str) -> IoResult<String> {
+    if path == "-" {
+        let mut text = String::new();
+        stdin().read_to_string(&mut text)?;
+        Ok(text)
+    } else {
+        read_to_string(path)
+    }
+}
+
+// the first word of what hash-git-object prints
+fn read_hash(path: &str) -> Result<String, Box<dyn Error>> {
+    let text = read_text(path)?;
+    match text.split_whitespace().next() {
+        Some(hash_str) => Ok(hash_str.to_string()),
+        None => Err(format!("No hash in {}", path).into()),
     }
+}
 
-    Some(())
+fn read_index(index_file: &Option<String>) -> IoResult<Vec<u8>> {
+    match index_file {
+        Some(path) => read(path),
+        None => {
+            let mut data = Vec::<u8>::new();
+            stdin().read_to_end(&mut data)?;
+            Ok(data)
+        }
+    }
 }
 
 pub fn try_main<I, T>(itr: I) -> Result<i32, Box<dyn Error>>
@@ -24,32 +127,128 @@ where
     I: IntoIterator<Item = T>,
     T: Into<OsString> + Clone,
 {
-    let args: Vec<String> = itr
+    let mut args: Vec<String> = itr
         .into_iter()
         .map(|i| i.into().to_string_lossy().into())
         .collect();
-    if args.len() < 4 {
-        eprintln!("Usage: patch-git-index <name> <size> <hash>");
+
+    let mut add_if_missing = false;
+    let mut hash_file = None;
+    let mut index_file = None;
+    // options come before everything else
+    loop {
+        match args.get(1).map(String::as_str) {
+            Some("--add-if-missing") => add_if_missing = true,
+            Some("--hash-file") if args.len() >= 3 => hash_file = Some(args.remove(2)),
+            Some("--index-file") if args.len() >= 3 => index_file = Some(args.remove(2)),
+            _ => break,
+        }
+        args.remove(1);
+    }
+
+    let batch = args.len() >= 3 && args[1] == "--batch";
+    let remove = args.len() >= 3 && args[1] == "--remove";
+    let edit_args = if hash_file.is_some() { 3 } else { 4 };
+    if !batch && !remove && args.len() < edit_args {
+        eprintln!("Usage: patch-git-index [options] <name> <size> <hash or - for stdin>");
+        eprintln!("       patch-git-index [options] --hash-file <file or -> <name> <size>");
+        eprintln!("       patch-git-index [options] --batch <file of name size hash lines or ->");
+        eprintln!("       patch-git-index [options] --remove <name>...");
+        eprintln!("Options: --add-if-missing, --index-file <index to read instead of stdin>");
         return Ok(1);
     }
 
-    let name_str = &args[1];
-    let size_str = &args[2];
-    let hash_str = &args[3];
+    if remove {
+        let names = &args[2..];
+        let mut data = read_index(&index_file)?;
+        let removed = match remove_entries(&mut data, names) {
+            Ok(removed) => removed,
+            Err(e) => {
+                eprintln!("Parse error: {}", e);
+                return Ok(2);
+            }
+        };
+        stdout().write_all(&data)?;
+        eprintln!("Removed {} of {} entries", removed, names.len());
+        if removed != names.len() {
+            return Ok(3);
+        }
+        return Ok(0);
+    }
 
-    let name = name_str.as_bytes();
-    let size = size_str.parse::<u32>()?;
-    let hash = git_index::Hash::from_hex(hash_str)?;
+    let edits = if batch {
+        if args[2] == "-" && index_file.is_none() {
+            eprintln!("The batch can only come from stdin if the index comes from --index-file");
+            return Ok(1);
+        }
+        parse_batch(&read_text(&args[2])?)?
+    } else {
+        let hash_path = match &hash_file {
+            Some(path) => path,
+            None if args[3] == "-" => &args[3],
+            None => "",
+        };
+        if hash_path == "-" && index_file.is_none() {
+            eprintln!("The hash can only come from stdin if the index comes from --index-file");
+            return Ok(1);
+        }
+        let hash_str = if hash_path.is_empty() {
+            args[3].clone()
+        } else {
+            read_hash(hash_path)?
+        };
+        vec![parse_edit(&args[1], &args[2], &hash_str)?]
+    };
+
+    let mut data = read_index(&index_file)?;
 
-    let mut data = Vec::<u8>::new();
-    stdin().read_to_end(&mut data)?;
+    let found = match patch_index(&mut data, &edits) {
+        Ok(found) => found,
+        Err(e) => {
+            eprintln!("Parse error: {}", e);
+            return Ok(2);
+        }
+    };
+
+    let missing: Vec<&Edit> = edits
+        .iter()
+        .zip(found.iter())
+        .filter(|(_, &f)| !f)
+        .map(|(edit, _)| edit)
+        .collect();
 
-    if patch_index(&mut data, name, size, &hash) == None {
-        eprintln!("Parse error");
-        return Ok(2);
+    if add_if_missing {
+        if let Err(e) = add_entries(&mut data, &missing) {
+            eprintln!("Parse error: {}", e);
+            return Ok(2);
+        }
+        stdout().write_all(&data)?;
+        eprintln!(
+            "Patched {} and added {} entries",
+            edits.len() - missing.len(),
+            missing.len()
+        );
+        return Ok(0);
     }
 
     stdout().write_all(&data)?;
 
+    if batch {
+        let found_count = found.iter().filter(|&&x| x).count();
+        eprintln!("Patched {} of {} entries", found_count, edits.len());
+        for edit in missing.iter() {
+            eprintln!("    not found: {}", String::from_utf8_lossy(&edit.name));
+        }
+        if found_count != edits.len() {
+            return Ok(3);
+        }
+    } else if !missing.is_empty() {
+        // the index is passed through unchanged
+        eprintln!(
+            "Warning: no entry named {}, nothing patched",
+            String::from_utf8_lossy(&edits[0].name)
+        );
+    }
+
     Ok(0)
 }
@@ -0,0 +1,40 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::io::{copy, stdin};
+use std::iter::Iterator;
+
+use la_tools::git_index;
+use la_tools::git_object;
+use la_tools::git_object::Digest;
+
+pub fn try_main<I, T>(itr: I) -> Result<i32, Box<dyn Error>>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let args: Vec<String> = itr
+        .into_iter()
+        .map(|i| i.into().to_string_lossy().into())
+        .collect();
+    if args.len() < 2 {
+        eprintln!("Usage: verify-git-object <expected hash> < <object>");
+        return Ok(1);
+    }
+
+    let expected = git_index::hash_from_hex(&args[1])?;
+
+    let (object_type, size, mut decode_read) = git_object::decode_sync_typed(stdin())?;
+
+    let mut digest = git_object::hash_sync_typed(object_type, size);
+    copy(&mut decode_read, &mut digest)?;
+    let value = digest.finalize();
+    println!("{:x}", value);
+
+    if value[..] == expected[..] {
+        println!("OK");
+        Ok(0)
+    } else {
+        println!("MISMATCH");
+        Ok(2)
+    }
+}